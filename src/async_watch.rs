@@ -0,0 +1,108 @@
+use crate::resolve::resolve_matches;
+use crate::wait::{self, UpdateState};
+use crate::{Mode, WatchError, WatchOptions};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::task::{self, JoinSet};
+use tokio::time::{self, MissedTickBehavior};
+
+/// Wait for the paths like [`FileWatcher::wait_all`](crate::FileWatcher::wait_all)
+/// but as a future, every path is checked by its own task on a tokio interval
+/// instead of by its own thread, so watching hundreds of them is cheap.
+///
+/// Needs a running tokio runtime, the checks themselves run on its blocking
+/// pool so http(s) and s3:// targets work too. The follow-up phases (wait_complete,
+/// expect_sha256, equals, dir_count, dir_empty), count and backoff are not applied.
+/// Lock files are not taken here either, call [`acquire_lock`](crate::acquire_lock)
+/// (which blocks) for every path before awaiting this.
+pub async fn watch_async(paths: Vec<String>, options: WatchOptions) -> Result<Vec<PathBuf>, WatchError> {
+    if paths.is_empty() {
+        return Err(WatchError::NoPaths);
+    }
+    let start = Instant::now();
+    let options = Arc::new(options);
+    let mut tasks = JoinSet::new();
+    for path in paths {
+        tasks.spawn(watch_one(path, Arc::clone(&options)));
+    }
+
+    // dropping the JoinSet on return aborts the watches still running
+    let mut ready = Vec::new();
+    loop {
+        let next = tokio::select! {
+            next = tasks.join_next() => next,
+            () = deadline(options.timeout.map(|timeout| start + timeout)) => {
+                warn!("Files were not ready after waiting {:?}, giving up", start.elapsed());
+                return Err(WatchError::Timeout);
+            }
+        };
+        match next {
+            None => return Ok(ready),
+            Some(Ok(path)) => {
+                let path = path?;
+                info!("File '{}' is ready", path);
+                ready.push(PathBuf::from(path));
+                if options.mode == Mode::Any {
+                    return Ok(ready);
+                }
+            }
+            Some(Err(e)) => panic!("watch task died: {}", e),
+        }
+    }
+}
+
+async fn deadline(at: Option<Instant>) {
+    match at {
+        Some(at) => time::sleep_until(at.into()).await,
+        None => future::pending().await,
+    }
+}
+
+// the same checks the blocking wait loops do, once per tick; they block (on
+// the disk, an http request or the runtime of the S3 client, which would
+// panic inside this one), so they run on the blocking pool
+
+async fn watch_one(path: String, opts: Arc<WatchOptions>) -> Result<String, WatchError> {
+    let mut ticks = time::interval(opts.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut state: Option<UpdateState> = match opts.update {
+        true => Some(wait::initial_state(&path, &opts)?),
+        false => None,
+    };
+    let skip: Arc<HashSet<PathBuf>> = Arc::new(match opts.new_only {
+        true => resolve_matches(&path, &opts).into_iter().collect(),
+        false => HashSet::new(),
+    });
+    loop {
+        // the first tick completes right away
+        ticks.tick().await;
+        let job = {
+            let (path, opts, skip) = (path.clone(), Arc::clone(&opts), Arc::clone(&skip));
+            move || {
+                let done = check(&path, &mut state, &opts, &skip);
+                (done, state)
+            }
+        };
+        let (done, checked) = task::spawn_blocking(job)
+            .await
+            .unwrap_or_else(|e| panic!("check of '{}' died: {}", path, e));
+        state = checked;
+        if let Some(done) = done? {
+            return Ok(done);
+        }
+    }
+}
+
+fn check(path: &str, state: &mut Option<UpdateState>, opts: &WatchOptions, skip: &HashSet<PathBuf>) -> Result<Option<String>, WatchError> {
+    if let Some(state) = state {
+        wait::check_update(path, state, opts)
+    } else if opts.until_gone {
+        Ok(wait::file_gone(path, opts).then(|| path.to_string()))
+    } else {
+        wait::file_available(path, opts, skip)
+    }
+}
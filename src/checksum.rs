@@ -0,0 +1,281 @@
+use clap::ValueEnum;
+use sha2::Digest;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+
+/// Incremental digest of a file's content, one adapter per algorithm
+pub trait Hasher {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self: Box<Self>) -> Vec<u8>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Checksum {
+    Sha256,
+    Sha1,
+    Md5,
+    Blake3,
+    Crc32,
+}
+
+impl Checksum {
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            Checksum::Sha256 => Box::new(sha2::Sha256::new()),
+            Checksum::Sha1 => Box::new(sha1::Sha1::new()),
+            Checksum::Md5 => Box::new(md5::Md5::new()),
+            Checksum::Blake3 => Box::new(blake3::Hasher::new()),
+            Checksum::Crc32 => Box::new(crc32fast::Hasher::new()),
+        }
+    }
+
+    /// Length of the digest written in hex
+    pub fn hex_len(self) -> usize {
+        match self {
+            Checksum::Sha256 | Checksum::Blake3 => 64,
+            Checksum::Sha1 => 40,
+            Checksum::Md5 => 32,
+            Checksum::Crc32 => 8,
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Checksum::Sha256 => write!(f, "sha256"),
+            Checksum::Sha1 => write!(f, "sha1"),
+            Checksum::Md5 => write!(f, "md5"),
+            Checksum::Blake3 => write!(f, "blake3"),
+            Checksum::Crc32 => write!(f, "crc32"),
+        }
+    }
+}
+
+// digest of an open file, read in chunks so large files are fine
+
+pub(crate) fn digest_file(file: &File, checksum: Checksum) -> io::Result<Vec<u8>> {
+    #[cfg(feature = "mmap")]
+    if let Some(digest) = mmap::digest(file, checksum) {
+        return Ok(digest);
+    }
+    let mut hasher = checksum.hasher();
+    let mut reader = BufReader::new(file);
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+// large files are mapped instead of read, so they go to the hasher without a
+// copy; windows refuses to truncate a mapped file, elsewhere reading the part
+// a truncation took away raises SIGBUS, see sigbus, and the file is read the
+// usual way instead
+
+#[cfg(feature = "mmap")]
+mod mmap {
+    use super::Checksum;
+    use log::debug;
+    use memmap2::Mmap;
+    use std::fs::File;
+
+    const MIN_LEN: u64 = 64 << 20;
+    const CHUNK: usize = 16 << 20;
+
+    pub(super) fn digest(file: &File, checksum: Checksum) -> Option<Vec<u8>> {
+        let len = file.metadata().ok()?.len();
+        if len < MIN_LEN {
+            return None;
+        }
+        // SAFETY: the mapping is private to this function and only read; a
+        // writer may change the bytes under it (which the digest then
+        // reflects, as a read would), a truncation may not take the pages
+        // away, the guard below puts zeros there and the digest is dropped
+        let map = match unsafe { Mmap::map(file) } {
+            Ok(map) => map,
+            Err(e) => {
+                debug!("Cannot map file, reading it instead: {}", e);
+                return None;
+            }
+        };
+        #[cfg(unix)]
+        let Some(guard) = sigbus::Guard::new(&map) else {
+            debug!("Too many files mapped at once, reading it instead");
+            return None;
+        };
+        let mut hasher = checksum.hasher();
+        for chunk in map.chunks(CHUNK) {
+            hasher.update(chunk);
+        }
+        #[cfg(unix)]
+        if guard.torn() {
+            debug!("File shrank while mapped, reading it instead");
+            return None;
+        }
+        Some(hasher.finish())
+    }
+
+    // a page of a mapping the file no longer covers raises SIGBUS, which
+    // kills the process by default; while a mapping is hashed, a handler
+    // maps zeros over such a page instead and flags the mapping as torn,
+    // faults anywhere else get the default action
+    #[cfg(unix)]
+    pub(super) mod sigbus {
+        use std::ptr;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use std::sync::Once;
+
+        // one slot per mapping being hashed, all the handler touches are
+        // these atomics; a claimed slot has start 1 and len 0 until the
+        // range is in
+        struct Slot {
+            start: AtomicUsize,
+            len: AtomicUsize,
+            torn: AtomicBool,
+        }
+
+        static SLOTS: [Slot; 64] =
+            [const { Slot { start: AtomicUsize::new(0), len: AtomicUsize::new(0), torn: AtomicBool::new(false) } }; 64];
+        static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+        static INSTALL: Once = Once::new();
+
+        pub(crate) struct Guard {
+            slot: &'static Slot,
+        }
+
+        impl Guard {
+            // None when every slot is taken
+            pub(crate) fn new(map: &[u8]) -> Option<Guard> {
+                INSTALL.call_once(install);
+                let slot = SLOTS
+                    .iter()
+                    .find(|slot| slot.start.compare_exchange(0, 1, Ordering::AcqRel, Ordering::Relaxed).is_ok())?;
+                slot.torn.store(false, Ordering::Relaxed);
+                slot.len.store(map.len(), Ordering::Release);
+                slot.start.store(map.as_ptr() as usize, Ordering::Release);
+                Some(Guard { slot })
+            }
+
+            pub(crate) fn torn(&self) -> bool {
+                self.slot.torn.load(Ordering::Acquire)
+            }
+        }
+
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                self.slot.len.store(0, Ordering::Release);
+                self.slot.start.store(0, Ordering::Release);
+            }
+        }
+
+        fn install() {
+            // SAFETY: sysconf and sigaction are called with valid arguments,
+            // the handler only uses async-signal-safe calls
+            unsafe {
+                PAGE_SIZE.store(libc::sysconf(libc::_SC_PAGESIZE) as usize, Ordering::Relaxed);
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_sigbus as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_SIGINFO;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(libc::SIGBUS, &action, ptr::null_mut());
+            }
+        }
+
+        extern "C" fn on_sigbus(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+            // SAFETY: the kernel passes a valid siginfo for SA_SIGINFO
+            let addr = unsafe { (*info).si_addr() } as usize;
+            let page_size = PAGE_SIZE.load(Ordering::Relaxed);
+            for slot in &SLOTS {
+                let start = slot.start.load(Ordering::Acquire);
+                let len = slot.len.load(Ordering::Acquire);
+                if addr < start || addr - start >= len {
+                    continue;
+                }
+                let page = addr & !(page_size - 1);
+                // SAFETY: the page is part of a live mapping of this slot,
+                // replacing it keeps the address range valid
+                let zeros = unsafe {
+                    libc::mmap(
+                        page as *mut libc::c_void,
+                        page_size,
+                        libc::PROT_READ,
+                        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                        -1,
+                        0,
+                    )
+                };
+                if zeros != libc::MAP_FAILED {
+                    slot.torn.store(true, Ordering::Release);
+                    return;
+                }
+            }
+            // not one of ours, the fault repeats and gets the default action
+            // SAFETY: resetting a handler is async-signal-safe
+            unsafe {
+                libc::signal(libc::SIGBUS, libc::SIG_DFL);
+            }
+        }
+    }
+}
+
+// sha2, sha1 and md-5 share the RustCrypto Digest trait
+
+macro_rules! digest_hasher {
+    ($($ty:ty),*) => {$(
+        impl Hasher for $ty {
+            fn update(&mut self, data: &[u8]) {
+                Digest::update(self, data);
+            }
+
+            fn finish(self: Box<Self>) -> Vec<u8> {
+                self.finalize().to_vec()
+            }
+        }
+    )*};
+}
+
+digest_hasher!(sha2::Sha256, sha1::Sha1, md5::Md5);
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.finalize().as_bytes().to_vec()
+    }
+}
+
+// big endian, like crc32 tools print it
+
+impl Hasher for crc32fast::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        crc32fast::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.finalize().to_be_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(unix, feature = "mmap"))]
+    #[test]
+    fn reading_a_truncated_mapping_gives_zeros() {
+        use std::io::Write;
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[1; 1 << 16]).unwrap();
+        // SAFETY: only read below, the truncation is what is tested
+        let map = unsafe { memmap2::Mmap::map(&file) }.unwrap();
+        let guard = super::mmap::sigbus::Guard::new(&map).unwrap();
+        file.set_len(0).unwrap();
+        assert_eq!(map[map.len() - 1], 0);
+        assert!(guard.torn());
+    }
+}
@@ -0,0 +1,77 @@
+use crate::{exit_usage, Args};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, CommandFactory};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use toml::{Table, Value};
+
+// command line with the --config file folded in: every key of the file is an
+// option name (lock_dir = "/run/fw", mode = "any", poll = true) turned into
+// flags put in front of the real ones; a flag given on the command line or
+// through its env var wins over the file, the file over the default
+
+pub(crate) fn args_with_config() -> Vec<OsString> {
+    let args: Vec<OsString> = env::args_os().collect();
+    let matches = Args::command().ignore_errors(true).get_matches_from(&args);
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return args;
+    };
+
+    let content = fs::read_to_string(path)
+        .unwrap_or_else(|e| fail(ErrorKind::Io, format!("cannot read config '{}': {}", path.display(), e)));
+    let table: Table = content
+        .parse()
+        .unwrap_or_else(|e| fail(ErrorKind::InvalidValue, format!("invalid config '{}': {}", path.display(), e)));
+
+    let command = Args::command();
+    let mut from_config = Vec::new();
+    for (key, value) in &table {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == key.as_str() && arg.get_long().is_some() && key != "config")
+        else {
+            fail(ErrorKind::UnknownArgument, format!("unknown option '{}' in config '{}'", key, path.display()));
+        };
+        if matches!(
+            matches.value_source(key),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+
+        let flag = format!("--{}", arg.get_long().unwrap_or_default());
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match (arg.get_action(), value) {
+                (ArgAction::SetTrue, Value::Boolean(set)) => {
+                    if *set {
+                        from_config.push(OsString::from(&flag));
+                    }
+                }
+                (ArgAction::Count, Value::Integer(count)) => {
+                    for _ in 0..*count {
+                        from_config.push(OsString::from(&flag));
+                    }
+                }
+                (ArgAction::SetTrue | ArgAction::Count, _) | (_, Value::Boolean(_) | Value::Table(_) | Value::Array(_)) => {
+                    fail(ErrorKind::InvalidValue, format!("invalid value for '{}' in config '{}'", key, path.display()));
+                }
+                (_, Value::String(value)) => from_config.push(OsString::from(format!("{}={}", flag, value))),
+                (_, value) => from_config.push(OsString::from(format!("{}={}", flag, value))),
+            }
+        }
+    }
+
+    let mut args = args.into_iter();
+    args.next().into_iter().chain(from_config).chain(args).collect()
+}
+
+fn fail(kind: ErrorKind, message: String) -> ! {
+    exit_usage(Args::command().error(kind, message))
+}
@@ -0,0 +1,19 @@
+use log::{debug, warn};
+use notify_rust::Notification;
+use std::path::Path;
+use std::time::Duration;
+
+// pop a desktop notification for a ready file, without a notification
+// daemon (headless systems) this only logs a warning
+
+pub fn notify_desktop(filename: &Path, waited: Duration) {
+    let res = Notification::new()
+        .summary(&format!("'{}' is ready", filename.display()))
+        .body(&format!("Waited {:.1}s", waited.as_secs_f64()))
+        .appname("filewatcher")
+        .show();
+    match res {
+        Ok(_) => debug!("Notification sent for '{}'", filename.display()),
+        Err(e) => warn!("Cannot send desktop notification: {}", e),
+    }
+}
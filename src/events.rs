@@ -0,0 +1,135 @@
+use crate::resolve::{matches_any, resolve_matches};
+use crate::ticker::Ticker;
+use crate::wait::file_id;
+use crate::{FileWatcher, WatchOptions};
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileEventKind {
+    /// The path started to exist (or to match the pattern)
+    Created,
+    /// Mod time, size or the file behind the path changed
+    Modified,
+    /// The path stopped to exist (or to match the pattern)
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEvent {
+    pub kind: FileEventKind,
+    pub path: PathBuf,
+    /// When the change was noticed, not when it happened
+    pub time: SystemTime,
+}
+
+// what a path looked like at the last check
+type Seen = (Option<SystemTime>, u64, (u64, u64));
+
+/// Iterator over the changes to the watched paths, see [`FileWatcher::events`]
+pub struct FileEvents {
+    paths: Vec<String>,
+    options: WatchOptions,
+    ticker: Ticker,
+    seen: HashMap<PathBuf, Seen>,
+    queue: VecDeque<FileEvent>,
+    start: Instant,
+}
+
+/// Changes to a path or to the matches of a pattern, with the default options
+pub fn watch_events(path: impl Into<String>) -> FileEvents {
+    FileWatcher::new(path).events()
+}
+
+impl FileWatcher {
+    /// Changes to the paths from now on, woken by filesystem events where the
+    /// platform has them and by polling every interval otherwise, ending once
+    /// the timeout (if any) elapsed. Every match of a wildcard or regex is a
+    /// path of its own: a new match is Created, one that no longer matches
+    /// (e.g. renamed away) Removed, the files there at the start are not
+    /// reported. Changes closer together than one check are one event
+    pub fn events(&self) -> FileEvents {
+        let ticker = Ticker::new(&self.paths, &self.options).with_counter(Arc::clone(&self.checks));
+        let mut events = FileEvents {
+            paths: self.paths.clone(),
+            options: self.options.clone(),
+            ticker,
+            seen: HashMap::new(),
+            queue: VecDeque::new(),
+            start: Instant::now(),
+        };
+        events.seen = events.snapshot();
+        events
+    }
+}
+
+impl FileEvents {
+    fn snapshot(&self) -> HashMap<PathBuf, Seen> {
+        let mut current = HashMap::new();
+        for path in &self.paths {
+            let candidates = match matches_any(path, &self.options) {
+                true => resolve_matches(path, &self.options),
+                false => vec![PathBuf::from(path)],
+            };
+            for candidate in candidates {
+                let metadata = match self.options.no_follow {
+                    true => fs::symlink_metadata(&candidate),
+                    false => fs::metadata(&candidate),
+                };
+                if let Ok(metadata) = metadata {
+                    current.insert(candidate, (metadata.modified().ok(), metadata.len(), file_id(&metadata)));
+                }
+            }
+        }
+        current
+    }
+
+    // compare a new scan to the last one, in path order so the events of
+    // one check come out the same every time
+    fn check(&mut self) {
+        self.ticker.count_check();
+        let current = self.snapshot();
+        let time = SystemTime::now();
+        let mut changes: Vec<(PathBuf, FileEventKind)> = Vec::new();
+        for (path, now) in &current {
+            match self.seen.get(path) {
+                None => changes.push((path.clone(), FileEventKind::Created)),
+                Some(before) if before != now => changes.push((path.clone(), FileEventKind::Modified)),
+                Some(_) => {}
+            }
+        }
+        for path in self.seen.keys() {
+            if !current.contains_key(path) {
+                changes.push((path.clone(), FileEventKind::Removed));
+            }
+        }
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        for (path, kind) in changes {
+            debug!("{:?} '{}'", kind, path.display());
+            self.queue.push_back(FileEvent { kind, path, time });
+        }
+        self.seen = current;
+    }
+}
+
+impl Iterator for FileEvents {
+    type Item = FileEvent;
+
+    fn next(&mut self) -> Option<FileEvent> {
+        loop {
+            if let Some(event) = self.queue.pop_front() {
+                return Some(event);
+            }
+            let left = self.options.timeout.map(|limit| limit.saturating_sub(self.start.elapsed()));
+            if left == Some(Duration::ZERO) {
+                return None;
+            }
+            self.ticker.wait_at_most(self.ticker.interval, left);
+            self.check();
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::WatchOptions;
+use clap::ValueEnum;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use ureq::Agent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HttpMethod {
+    /// Only ask for the headers
+    Head,
+    /// Fetch the object, for servers that do not answer HEAD
+    Get,
+}
+
+// a check that hangs must not hold up the timeout for long
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// status of the last answer per URL, for the summary
+static STATUSES: Mutex<Option<HashMap<String, u16>>> = Mutex::new(None);
+
+// an URL counts as available once it answers 2xx, anything else (including
+// no answer at all) means not yet
+
+pub(crate) fn url_available(url: &str, opts: &WatchOptions) -> bool {
+    let agent: Agent = Agent::config_builder()
+        .timeout_global(Some(REQUEST_TIMEOUT))
+        .http_status_as_error(false)
+        .build()
+        .into();
+    let res = match opts.http_method {
+        HttpMethod::Head => agent.head(url).call(),
+        HttpMethod::Get => agent.get(url).call(),
+    };
+    match res {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            debug!("URL '{}' answered {}", url, status);
+            STATUSES
+                .lock()
+                .unwrap()
+                .get_or_insert_with(HashMap::new)
+                .insert(url.to_string(), status);
+            response.status().is_success()
+        }
+        Err(e) => {
+            debug!("Cannot reach '{}': {}", url, e);
+            false
+        }
+    }
+}
+
+/// Status code of the last answer from the URL, None if it never answered
+pub fn last_status(url: &str) -> Option<u16> {
+    STATUSES.lock().unwrap().as_ref()?.get(url).copied()
+}
@@ -0,0 +1,540 @@
+//! Wait for files to appear or change.
+//!
+//! ```no_run
+//! use file_watcher::FileWatcher;
+//!
+//! let path = FileWatcher::new("/data/out-*.csv").wait().unwrap();
+//! println!("{}", path.display());
+//! ```
+
+#[cfg(feature = "async")]
+mod async_watch;
+mod checksum;
+#[cfg(feature = "desktop")]
+mod desktop;
+mod events;
+#[cfg(feature = "http")]
+mod http;
+mod lock;
+mod metrics;
+#[cfg(feature = "progress")]
+mod progress;
+mod resolve;
+#[cfg(feature = "s3")]
+mod s3;
+mod state;
+#[cfg(feature = "systemd")]
+mod systemd;
+mod ticker;
+mod wait;
+#[cfg(feature = "webhook")]
+mod webhook;
+
+#[cfg(feature = "async")]
+pub use async_watch::watch_async;
+pub use checksum::{Checksum, Hasher};
+pub use events::{watch_events, FileEvent, FileEventKind, FileEvents};
+#[cfg(feature = "desktop")]
+pub use desktop::notify_desktop;
+#[cfg(feature = "http")]
+pub use http::{last_status, HttpMethod};
+pub use lock::{
+    acquire_lock, clean_locks, default_lock_dir, lock_dir, lock_path, remove_lock_file, LockGuard, LockOptions,
+};
+#[cfg(feature = "s3")]
+pub use s3::{last_object, S3Object};
+#[cfg(feature = "progress")]
+pub use progress::suspend_progress;
+#[cfg(feature = "systemd")]
+pub use systemd::notify_ready;
+pub use resolve::{is_excluded, is_pattern, is_url, normalize_path, resolve_all, resolve_file_name, resolve_regex, split_regex};
+#[cfg(feature = "webhook")]
+pub use webhook::send_webhook;
+
+use chrono::Utc;
+use clap::ValueEnum;
+use regex::Regex;
+use serde_json::json;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use metrics::Metrics;
+use ticker::Ticker;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    /// A lock file could not be created or locked
+    CannotLock,
+    /// The watched path is a directory
+    IsDir,
+    /// The watched file disappeared while waiting for an update
+    FileMissing,
+    /// The timeout elapsed before the file was ready
+    Timeout,
+    /// The timeout elapsed while the file did not match the expected checksum
+    ChecksumMismatch,
+    /// The mod time of a file could not be read or lies before the epoch
+    InvalidTime,
+    /// The file exists but its metadata or content may not be read
+    PermissionDenied,
+    /// A file turned up where a directory was expected
+    NotDir,
+    /// The watcher was given no paths to watch
+    NoPaths,
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchError::CannotLock => write!(f, "cannot obtain lock"),
+            WatchError::IsDir => write!(f, "path is a directory"),
+            WatchError::FileMissing => write!(f, "file went missing"),
+            WatchError::Timeout => write!(f, "timed out"),
+            WatchError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            WatchError::InvalidTime => write!(f, "invalid mod time"),
+            WatchError::PermissionDenied => write!(f, "permission denied"),
+            WatchError::NotDir => write!(f, "path is not a directory"),
+            WatchError::NoPaths => write!(f, "no paths to watch"),
+        }
+    }
+}
+
+impl std::error::Error for WatchError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// Return as soon as one of the files is ready
+    Any,
+    /// Return once every file is ready
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MatchPolicy {
+    /// The alphabetically first match
+    First,
+    /// The most recently modified match
+    Newest,
+    /// The least recently modified match
+    Oldest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TimeField {
+    /// Modification time, changes when the content is written
+    Mtime,
+    /// Inode change time, also changes on chmod, chown, link and rename (Unix only, mtime elsewhere)
+    Ctime,
+    /// Access time, often coarse or disabled by relatime/noatime mounts
+    Atime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChangeSignal {
+    /// The mod time (or the --time-field one) moved forward
+    Mtime,
+    /// The size changed, e.g. truncated in place
+    Size,
+    /// Another file is behind the path, e.g. an atomic rename, log rotation
+    /// or a repointed symlink
+    Inode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Human readable log lines only
+    Text,
+    /// Also print newline delimited JSON events to stdout
+    Json,
+}
+
+// settings shared by the wait loops
+
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// Wait for the files to be updated instead of to appear
+    pub update: bool,
+    /// Wait for the files to be removed instead of to appear
+    pub until_gone: bool,
+    pub mode: Mode,
+    pub interval: Duration,
+    pub timeout: Option<Duration>,
+    /// Give up after this many checks, like an elapsed timeout
+    pub max_checks: Option<u64>,
+    /// Log that the wait is still going every this many checks, 0 disables it
+    pub heartbeat: u64,
+    /// Factor the interval grows by after every check no event cut short
+    pub backoff: f64,
+    /// Upper bound for the backed off interval, one hour when None
+    pub max_interval: Option<Duration>,
+    /// Randomize every sleep by up to this fraction of it either way
+    pub jitter: f64,
+    /// Poll instead of using filesystem events
+    pub poll: bool,
+    /// Wait until an updated file stopped changing for this long
+    pub stable: Option<Duration>,
+    pub min_size: Option<u64>,
+    /// Only treat the files as ready once they have exactly this size
+    pub exact_size: Option<u64>,
+    /// With exact_size, a file grown past the size is ready too instead of
+    /// being waited on
+    pub oversize_ready: bool,
+    /// A pattern is only ready once at least this many files match it
+    pub count: Option<u64>,
+    /// Only treat the files as ready once their content matches
+    pub content_match: Option<Regex>,
+    /// How much of the file content_match looks at, and the longest line
+    /// tail_match keeps
+    pub content_max_bytes: u64,
+    /// Wait for a line matching this to be appended to the files instead of
+    /// for them to appear
+    pub tail_match: Option<Regex>,
+    /// Once the files appeared, wait until their size stayed the same for this long
+    pub wait_complete: Option<Duration>,
+    /// Lowercase hex digest (of the checksum algorithm) the files have to
+    /// match before they count as ready
+    pub expect_sha256: Option<String>,
+    /// Once the files appeared, also wait until their content is the same as
+    /// this file's
+    pub equals: Option<String>,
+    /// Digest used for expect_sha256, equals, by_content and ignore_touch
+    pub checksum: Checksum,
+    /// Which file a pattern resolves to when several match
+    pub match_policy: MatchPolicy,
+    /// Treat the filename part of the paths as a regular expression
+    pub regex: bool,
+    /// Wait for directories instead of files
+    pub dir: bool,
+    /// With dir, also wait until each directory has at least this many entries
+    pub dir_count: Option<u64>,
+    /// With dir, also wait until no entries are left in each directory
+    pub dir_empty: bool,
+    /// With dir_empty, fail right away when a directory does not exist yet
+    pub dir_must_exist: bool,
+    /// Only count directory entries whose name matches
+    pub dir_glob: Option<glob::Pattern>,
+    /// Do not count directory entries starting with a dot
+    pub ignore_hidden: bool,
+    /// Skip matches of a wildcard or regex whose name matches one of these
+    pub exclude: Vec<glob::Pattern>,
+    /// Ignore files that already existed when the wait started
+    pub new_only: bool,
+    /// In update mode, wait until the files are newer than this one instead
+    pub newer_than: Option<String>,
+    /// In update mode, wait until the files were modified after this Unix time
+    pub since: Option<u64>,
+    /// Look at symlinks themselves instead of the files they point to
+    pub no_follow: bool,
+    /// How http(s) targets are requested
+    #[cfg(feature = "http")]
+    pub http_method: HttpMethod,
+    /// An s3 target is only ready once its etag is this one
+    #[cfg(feature = "s3")]
+    pub s3_etag: Option<String>,
+    /// Show a progress bar of the file size while waiting for min_size,
+    /// exact_size or expected_size, on stderr when it is a terminal
+    #[cfg(feature = "progress")]
+    pub progress: bool,
+    /// Size the file ends up at, only the progress bar uses it
+    #[cfg(feature = "progress")]
+    pub expected_size: Option<u64>,
+    /// Keep Prometheus textfile collector metrics about the wait in this file
+    pub metrics_file: Option<PathBuf>,
+    /// The file the log goes to, writing it does not wake the wait loops
+    /// even when it is in a watched directory
+    pub log_file: Option<PathBuf>,
+    /// Which timestamp update mode compares
+    pub time_field: TimeField,
+    /// What update mode counts as an update of a file, any one of them is
+    pub detect: Vec<ChangeSignal>,
+    /// Compare content digests instead of mod times in update mode
+    pub by_content: bool,
+    /// In update mode, a newer mod time alone is not an update, the size or
+    /// content has to change too
+    pub ignore_touch: bool,
+    /// In update mode, a file that disappears may come back within this long
+    /// instead of failing right away, what changes after its return counts
+    pub tolerate_missing: Option<Duration>,
+    /// In update mode, a file is only done after it was updated this many
+    /// times, each update taking a new baseline
+    pub updates: Option<u64>,
+    /// In update mode, only a file getting smaller than it was (e.g.
+    /// truncated by log rotation) is an update
+    pub on_shrink: bool,
+    /// Keep waiting when a file may not be read instead of failing
+    pub retry_permission: bool,
+    /// In update mode, compare against the baseline stored here by the last
+    /// run and store the current one when the wait ends
+    pub state_file: Option<PathBuf>,
+    pub format: Format,
+}
+
+impl Default for WatchOptions {
+    fn default() -> WatchOptions {
+        WatchOptions {
+            update: false,
+            until_gone: false,
+            mode: Mode::All,
+            interval: Duration::from_secs(10),
+            timeout: None,
+            max_checks: None,
+            heartbeat: 6,
+            backoff: 1.0,
+            max_interval: None,
+            jitter: 0.0,
+            poll: false,
+            stable: None,
+            min_size: None,
+            exact_size: None,
+            oversize_ready: false,
+            count: None,
+            content_match: None,
+            content_max_bytes: 1 << 20,
+            tail_match: None,
+            wait_complete: None,
+            expect_sha256: None,
+            equals: None,
+            checksum: Checksum::Sha256,
+            match_policy: MatchPolicy::Newest,
+            regex: false,
+            dir: false,
+            dir_count: None,
+            dir_empty: false,
+            dir_must_exist: false,
+            dir_glob: None,
+            ignore_hidden: false,
+            exclude: Vec::new(),
+            new_only: false,
+            newer_than: None,
+            since: None,
+            no_follow: false,
+            #[cfg(feature = "http")]
+            http_method: HttpMethod::Head,
+            #[cfg(feature = "s3")]
+            s3_etag: None,
+            #[cfg(feature = "progress")]
+            progress: false,
+            #[cfg(feature = "progress")]
+            expected_size: None,
+            metrics_file: None,
+            log_file: None,
+            time_field: TimeField::Mtime,
+            detect: vec![ChangeSignal::Mtime, ChangeSignal::Size, ChangeSignal::Inode],
+            by_content: false,
+            ignore_touch: false,
+            tolerate_missing: None,
+            updates: None,
+            on_shrink: false,
+            retry_permission: false,
+            state_file: None,
+            format: Format::Text,
+        }
+    }
+}
+
+pub struct FileWatcher {
+    paths: Vec<String>,
+    options: WatchOptions,
+    checks: Arc<AtomicU64>,
+}
+
+impl FileWatcher {
+    pub fn new(path: impl Into<String>) -> FileWatcher {
+        FileWatcher::with_paths(vec![path.into()])
+    }
+
+    pub fn with_paths(paths: Vec<String>) -> FileWatcher {
+        FileWatcher {
+            paths,
+            options: WatchOptions::default(),
+            checks: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn options(mut self, options: WatchOptions) -> FileWatcher {
+        self.options = options;
+        self
+    }
+
+    /// How often the files were checked so far, over all waits
+    pub fn checks(&self) -> u64 {
+        self.checks.load(Ordering::Relaxed)
+    }
+
+    /// Block until the file is ready, returns the resolved path (the first one
+    /// when watching several files)
+    pub fn wait(&self) -> Result<PathBuf, WatchError> {
+        self.wait_all()?.into_iter().next().ok_or(WatchError::NoPaths)
+    }
+
+    /// Block until the files are ready according to the mode, returns the
+    /// resolved paths of the ready files
+    pub fn wait_all(&self) -> Result<Vec<PathBuf>, WatchError> {
+        if self.paths.is_empty() {
+            return Err(WatchError::NoPaths);
+        }
+        if !self.options.until_gone && !self.options.dir {
+            for path in &self.paths {
+                if !resolve::matches_any(path, &self.options) {
+                    wait::reject_dir(path)?;
+                }
+            }
+        }
+        if let Some(reference) = &self.options.equals {
+            wait::require_file(reference)?;
+        }
+        if self.options.dir_must_exist {
+            for path in &self.paths {
+                wait::require_dir(path)?;
+            }
+        }
+        let start = Instant::now();
+        let metrics = self.metrics(start);
+        #[cfg(feature = "progress")]
+        progress::start(&self.paths, &self.options);
+        let res = self.wait_phases(start, &metrics);
+        #[cfg(feature = "progress")]
+        progress::finish();
+        if let Some(metrics) = &metrics {
+            metrics.write(self.checks());
+        }
+        if let Some(file) = &self.options.state_file
+            && self.options.update
+        {
+            state::save(file, &self.paths, &self.options);
+        }
+        res
+    }
+
+    // the wait itself, then what appearance and --dir modes wait for after it
+    fn wait_phases(&self, start: Instant, metrics: &Option<Arc<Metrics>>) -> Result<Vec<PathBuf>, WatchError> {
+        let paths = if self.options.mode == Mode::Any && self.paths.len() > 1 {
+            self.wait_any(metrics)?
+        } else {
+            let ticker = self.ticker(&self.paths, metrics);
+            wait_for(&self.paths, &ticker, &self.options)?
+        };
+        if !self.options.update && !self.options.until_gone && !self.options.dir && self.options.tail_match.is_none() {
+            let ticker = self.ticker(&paths, metrics);
+            if let Some(window) = self.options.wait_complete {
+                wait::wait_until_complete(&paths, window, &ticker, &self.options, start)?;
+            }
+            if let Some(expected) = &self.options.expect_sha256 {
+                wait::wait_for_checksum(&paths, expected, &ticker, &self.options, start)?;
+            }
+            if let Some(reference) = &self.options.equals {
+                wait::wait_for_equal(&paths, reference, &ticker, &self.options, start)?;
+            }
+        }
+        if (self.options.dir_count.is_some() || self.options.dir_empty)
+            && self.options.dir
+            && !self.options.until_gone
+        {
+            // watch what is inside the directories rather than next to them
+            let contents: Vec<String> = paths
+                .iter()
+                .map(|dir| Path::new(dir).join("*").to_string_lossy().into_owned())
+                .collect();
+            let ticker = self.ticker(&contents, metrics);
+            wait::wait_for_dir_entries(&paths, &ticker, &self.options, start)?;
+        }
+        Ok(paths.into_iter().map(PathBuf::from).collect())
+    }
+
+    /// Call on_file for every file that starts matching one of the paths,
+    /// until the timeout elapses (or forever without one)
+    pub fn stream(&self, mut on_file: impl FnMut(&Path)) -> Result<(), WatchError> {
+        if self.paths.is_empty() {
+            return Err(WatchError::NoPaths);
+        }
+        let metrics = self.metrics(Instant::now());
+        let ticker = self.ticker(&self.paths, &metrics);
+        wait::stream_new_files(&self.paths, &ticker, &self.options, &mut on_file)
+    }
+
+    // what every ticker of one wait reports to
+    fn metrics(&self, start: Instant) -> Option<Arc<Metrics>> {
+        let file = self.options.metrics_file.clone()?;
+        Some(Arc::new(Metrics::new(file, &self.paths, &self.options, start)))
+    }
+
+    fn ticker(&self, paths: &[String], metrics: &Option<Arc<Metrics>>) -> Ticker {
+        Ticker::new(paths, &self.options)
+            .with_counter(Arc::clone(&self.checks))
+            .with_metrics(metrics.clone())
+    }
+
+    // one thread per file so a slow check of one does not hold up the
+    // others, the first result wins; the losing threads give up at their
+    // next check and hold no locks, those stay with the caller
+    fn wait_any(&self, metrics: &Option<Arc<Metrics>>) -> Result<Vec<String>, WatchError> {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::channel();
+        for path in &self.paths {
+            let paths = vec![path.clone()];
+            let options = self.options.clone();
+            let tx = tx.clone();
+            let cancel = Arc::clone(&cancel);
+            let checks = Arc::clone(&self.checks);
+            let metrics = metrics.clone();
+            thread::spawn(move || {
+                let ticker = Ticker::new(&paths, &options)
+                    .with_cancel(cancel)
+                    .with_counter(checks)
+                    .with_metrics(metrics);
+                let _ = tx.send(wait_for(&paths, &ticker, &options));
+            });
+        }
+        drop(tx);
+
+        // every thread sends before it ends, nothing received means they panicked
+        let res = rx.recv().expect("watch threads died");
+        cancel.store(true, Ordering::Relaxed);
+        res
+    }
+}
+
+fn wait_for(paths: &[String], ticker: &Ticker, options: &WatchOptions) -> Result<Vec<String>, WatchError> {
+    if let Some(regex) = &options.tail_match {
+        wait::wait_for_tail_match(paths, regex, ticker, options)
+    } else if options.update {
+        wait::wait_for_file_update(paths, ticker, options)
+    } else if options.until_gone {
+        wait::wait_for_file_gone(paths, ticker, options)
+    } else {
+        wait::wait_for_file(paths, ticker, options)
+    }
+}
+
+// print a JSON event line, independent of the log level
+
+pub(crate) fn emit_event(format: Format, event: &str, filename: &str) {
+    if format == Format::Json {
+        let line = json!({
+            "timestamp": Utc::now().to_rfc3339(),
+            "event": event,
+            "filename": filename,
+        });
+        println!("{}", line);
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_paths_is_an_error() {
+        let watcher = FileWatcher::with_paths(vec![]);
+        assert_eq!(watcher.wait(), Err(WatchError::NoPaths));
+        assert_eq!(watcher.wait_all(), Err(WatchError::NoPaths));
+        assert_eq!(watcher.stream(|_| {}), Err(WatchError::NoPaths));
+    }
+}
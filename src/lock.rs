@@ -0,0 +1,413 @@
+use crate::resolve::normalize_path;
+use crate::{emit_event, is_url, to_hex, Format, WatchError};
+use chrono::Utc;
+use fs2::FileExt;
+use log::{debug, error, info, warn};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::env;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// lock on a stale file, the file is removed when the guard goes out of scope
+// (a shared one only by the last watcher holding it)
+
+pub struct LockGuard {
+    lock: File,
+    path: PathBuf,
+    shared: bool,
+}
+
+impl LockGuard {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        // upgrading only works once no other watcher shares the lock
+        if !self.shared || self.lock.try_lock_exclusive().is_ok() {
+            remove_lock_file(&self.path);
+        }
+        if let Err(e) = FileExt::unlock(&self.lock) {
+            debug!("Failed to unlock '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+// how acquire_lock behaves when another watcher holds the lock
+
+#[derive(Debug, Clone)]
+pub struct LockOptions {
+    pub format: Format,
+    /// Keep retrying this long instead of failing right away
+    pub wait: Option<Duration>,
+    /// Take a shared lock, only an exclusive one held elsewhere conflicts
+    pub shared: bool,
+}
+
+impl Default for LockOptions {
+    fn default() -> LockOptions {
+        LockOptions {
+            format: Format::Text,
+            wait: None,
+            shared: false,
+        }
+    }
+}
+
+pub fn acquire_lock(filename: &str, lock_dir: &Path, options: &LockOptions) -> Result<LockGuard, WatchError> {
+    let (mut lock, lock_file) = create_lock_file(filename, lock_dir)?;
+    let start = Instant::now();
+    let mut delay = Duration::from_millis(100);
+    loop {
+        let res = match options.shared {
+            true => FileExt::try_lock_shared(&lock),
+            false => lock.try_lock_exclusive(),
+        };
+        match res {
+            Ok(()) if still_linked(&lock, &lock_file) => break,
+            // the previous owner removed the file while we were waiting on
+            // it, a lock on the unlinked file would not stop anyone
+            Ok(()) => {
+                debug!("Lock file '{}' was removed by its owner, reopening", lock_file.display());
+                lock = create_lock_file(filename, lock_dir)?.0;
+            }
+            Err(e) => match options.wait {
+                Some(wait) if start.elapsed() < wait => {
+                    debug!("Lock on '{}' is held, retrying in {:?}: {}", lock_file.display(), delay, e);
+                    sleep(delay.min(wait.saturating_sub(start.elapsed())));
+                    delay = (delay * 2).min(Duration::from_secs(2));
+                }
+                Some(_) => {
+                    error!(
+                        "Cannot obtain lock on '{}' after waiting {:?}: {}",
+                        lock_file.display(),
+                        start.elapsed(),
+                        e
+                    );
+                    return Err(WatchError::CannotLock);
+                }
+                None => {
+                    error!("Cannot obtain lock on '{}': {}", lock_file.display(), e);
+                    return Err(WatchError::CannotLock);
+                }
+            },
+        }
+    }
+
+    // shared holders would overwrite each other's owner
+    if !options.shared {
+        record_owner(&lock, &lock_file, filename);
+    }
+    info!("Stale file generated '{}'", lock_file.display());
+    emit_event(options.format, "lock_acquired", filename);
+    Ok(LockGuard {
+        lock,
+        path: lock_file,
+        shared: options.shared,
+    })
+}
+
+#[cfg(unix)]
+fn still_linked(lock: &File, lock_file: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (lock.metadata(), fs::metadata(lock_file)) {
+        (Ok(locked), Ok(current)) => (locked.dev(), locked.ino()) == (current.dev(), current.ino()),
+        _ => false,
+    }
+}
+
+// an open file cannot be removed here
+#[cfg(not(unix))]
+fn still_linked(_lock: &File, _lock_file: &Path) -> bool {
+    true
+}
+
+// who holds the lock, for --clean-locks and for a `cat` on a stuck watcher;
+// rewritten in place and only then cut to length, so a reader sees a
+// complete owner rather than an empty file
+
+fn record_owner(mut lock: &File, lock_file: &Path, filename: &str) {
+    let owner = format!(
+        "pid={}\nfilename={}\nstarted={}\n",
+        std::process::id(),
+        filename,
+        Utc::now().to_rfc3339()
+    );
+    let res = lock
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| lock.write_all(owner.as_bytes()))
+        .and_then(|()| lock.set_len(owner.len() as u64));
+    if let Err(e) = res {
+        debug!("Cannot write owner to '{}': {}", lock_file.display(), e);
+    }
+}
+
+// PID recorded by record_owner, lock files from older versions are empty
+
+fn lock_owner(content: &str) -> Option<&str> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("pid="))
+        .map(str::trim)
+}
+
+// remove the lock files nobody holds a lock on, left behind by a watcher
+// that was killed, returns how many were removed
+
+pub fn clean_locks(lock_dir: &Path) -> usize {
+    let entries = match fs::read_dir(lock_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Cannot read lock dir '{}': {}", lock_dir.display(), e);
+            return 0;
+        }
+    };
+
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') || !path.is_file() {
+            continue;
+        }
+        let Ok(mut lock) = OpenOptions::new().read(true).write(true).open(&path) else {
+            continue;
+        };
+        if lock.try_lock_exclusive().is_err() {
+            debug!("Lock '{}' is in use", path.display());
+            continue;
+        }
+        let mut owner = String::new();
+        let _ = lock.read_to_string(&mut owner);
+        let owner = lock_owner(&owner).unwrap_or("unknown");
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                info!("Removed stale lock '{}' (pid {})", path.display(), owner);
+                removed += 1;
+            }
+            Err(e) => warn!("Cannot remove stale lock '{}': {}", path.display(), e),
+        }
+        let _ = FileExt::unlock(&lock);
+    }
+    removed
+}
+
+// what to do about a lock dir that cannot be used, part of every error about one
+const LOCK_DIR_HINT: &str = "pass a writable directory with --lock-dir or run with --no-lock";
+
+// the default lock dir is only checked here, when the first lock is taken
+
+fn create_lock_file(filename: &str, lock_dir: &Path) -> Result<(File, PathBuf), WatchError> {
+    if let Err(e) = create_lock_dir(lock_dir) {
+        error!("Failed to create lock dir '{}': {}, {}", lock_dir.display(), e, LOCK_DIR_HINT);
+        return Err(WatchError::CannotLock);
+    }
+    let lock_path = lock_path(filename, lock_dir);
+
+    let lock = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path);
+    match lock {
+        Ok(lock) => Ok((lock, lock_path)),
+        Err(e) => {
+            error!("Failed to open lock '{}': {}, {}", lock_path.display(), e, LOCK_DIR_HINT);
+            Err(WatchError::CannotLock)
+        }
+    }
+}
+
+// lock file acquire_lock uses for a watched path, the same for every
+// spelling of it; a relative one is taken from the working directory, so f
+// and $PWD/f share a lock and f watched from two directories does not
+
+pub fn lock_path(filename: &str, lock_dir: &Path) -> PathBuf {
+    let absolute = match is_url(filename) || Path::new(filename).is_absolute() {
+        true => None,
+        false => env::current_dir().ok().map(|dir| dir.join(filename)),
+    };
+    let filename = absolute.as_deref().map_or(Cow::Borrowed(filename), Path::to_string_lossy);
+    lock_dir.join(sanitize(&normalize_path(&filename)))
+}
+
+// resolve the lock dir, an explicit one is created if missing and must be writable
+
+pub fn lock_dir(custom: Option<PathBuf>) -> Result<PathBuf, WatchError> {
+    let Some(lock_dir) = custom else {
+        return default_lock_dir(true);
+    };
+
+    if let Err(e) = create_lock_dir(&lock_dir) {
+        error!("Failed to create lock dir '{}': {}, {}", lock_dir.display(), e, LOCK_DIR_HINT);
+        return Err(WatchError::CannotLock);
+    }
+
+    let probe = lock_dir.join(format!(".probe_{}", std::process::id()));
+    match File::create(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            Ok(lock_dir)
+        }
+        Err(e) => {
+            error!("Lock dir '{}' is not writable: {}, {}", lock_dir.display(), e, LOCK_DIR_HINT);
+            Err(WatchError::CannotLock)
+        }
+    }
+}
+
+// another watcher creating the same directory at the same time is fine, one
+// we create is private since the lock names give away the watched paths
+
+fn create_lock_dir(lock_dir: &Path) -> io::Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(lock_dir)
+}
+
+// env vars checked in order for the directory holding the lock files, with
+// the directory under them to use; runtime dirs (tmpfs on most systems) come
+// first, the bare home dir is where older versions kept their locks
+
+#[cfg(windows)]
+const LOCK_BASES: &[(&str, &str)] = &[("LOCALAPPDATA", ""), ("USERPROFILE", "")];
+#[cfg(not(windows))]
+const LOCK_BASES: &[(&str, &str)] = &[
+    ("XDG_RUNTIME_DIR", ""),
+    ("XDG_STATE_HOME", ""),
+    ("HOME", ".local/state"),
+    ("HOME", ""),
+];
+
+// with create false (e.g. for --dry-run) nothing is created, the directory
+// is where the locks would go if it can be created then
+
+pub fn default_lock_dir(create: bool) -> Result<PathBuf, WatchError> {
+    let Some(base_dir) = lock_base_dir(create) else {
+        let mut vars: Vec<&str> = LOCK_BASES.iter().map(|(var, _)| *var).collect();
+        vars.dedup();
+        error!("Cannot determine lock dir, none of {:?} is set to an existing directory, {}", vars, LOCK_DIR_HINT);
+        return Err(WatchError::CannotLock);
+    };
+    Ok(base_dir.join("filewatcher"))
+}
+
+// the first base that is set and exists, a missing directory under it (like
+// ~/.local/state on a fresh account) is created when create is set, env vars
+// are trusted as is
+fn lock_base_dir(create: bool) -> Option<PathBuf> {
+    LOCK_BASES.iter().find_map(|(var, sub)| {
+        let dir = env::var_os(var).filter(|dir| !dir.is_empty())?;
+        let base = PathBuf::from(dir);
+        if !base.is_dir() {
+            debug!("Skipping ${} for the lock dir, '{}' does not exist", var, base.display());
+            return None;
+        }
+        let base = base.join(sub);
+        if !create {
+            return Some(base);
+        }
+        if let Err(e) = fs::create_dir_all(&base) {
+            debug!("Skipping '{}' for the lock dir: {}", base.display(), e);
+            return None;
+        }
+        Some(base)
+    })
+}
+
+// readable part of a lock name, cut well below the usual 255 byte limit
+// for a file name
+const MAX_LOCK_PREFIX: usize = 183;
+
+// lock name for a watched path, the replaced characters make e.g. a.txt and
+// a-txt look the same, so a digest of the original path keeps them apart
+
+fn sanitize(input: &str) -> String {
+    let regex = Regex::new(r"[^a-zA-Z0-9]").unwrap();
+    let mut name = regex.replace_all(input, "_").to_string();
+    // the name is ASCII only, any byte index is a char boundary
+    name.truncate(MAX_LOCK_PREFIX);
+    let digest = to_hex(&Sha256::digest(input.as_bytes()));
+    format!("{}_{}", name, &digest[..16])
+}
+
+// the signal handler and the normal cleanup may both get here, and so may
+// --clean-locks from another watcher, an already removed file is fine
+
+pub fn remove_lock_file(lock_file: &Path) {
+    match fs::remove_file(lock_file) {
+        Ok(()) => debug!("Removed lock '{}'", lock_file.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("Lock '{}' was already removed", lock_file.display())
+        }
+        Err(e) => warn!("Cannot remove lock '{}': {}", lock_file.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropping_the_guard_removes_the_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = acquire_lock("/some/file", dir.path(), &LockOptions::default()).unwrap();
+        let lock_file = guard.path().to_path_buf();
+        assert!(lock_file.exists());
+        drop(guard);
+        assert!(!lock_file.exists());
+    }
+
+    #[test]
+    fn relative_paths_lock_like_absolute_ones() {
+        let dir = Path::new("/locks");
+        let cwd = env::current_dir().unwrap();
+        assert_eq!(lock_path("f", dir), lock_path(&cwd.join("f").to_string_lossy(), dir));
+        assert_eq!(lock_path("./x/../f", dir), lock_path("f", dir));
+        assert_ne!(lock_path("f", dir), lock_path("sub/f", dir));
+    }
+
+    #[test]
+    fn long_paths_get_short_lock_names() {
+        let path = format!("/{}", "d/".repeat(200));
+        assert_eq!(path.len(), 401);
+        let name = sanitize(&path);
+        assert!(name.len() <= 200, "{}", name.len());
+        assert_ne!(name, sanitize(&format!("{}x", path)));
+        // short names stay readable
+        assert!(sanitize("/tmp/in.csv").starts_with("_tmp_in_csv_"));
+    }
+
+    #[test]
+    fn paths_sanitizing_alike_get_distinct_locks() {
+        let dir = Path::new("/locks");
+        assert_ne!(lock_path("/tmp/a.txt", dir), lock_path("/tmp/a-txt", dir));
+        assert_ne!(lock_path("/tmp/a b", dir), lock_path("/tmp/a_b", dir));
+    }
+
+    #[test]
+    fn removing_a_lock_twice_is_fine() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_file = dir.path().join("lock");
+        File::create(&lock_file).unwrap();
+        remove_lock_file(&lock_file);
+        remove_lock_file(&lock_file);
+        assert!(!lock_file.exists());
+    }
+}
@@ -1,200 +1,1482 @@
-use clap::Parser;
-use fs2::FileExt;
-use log::{error, info, warn, debug};
+mod config;
+
+use chrono::{DateTime, Utc};
+use clap::builder::BoolishValueParser;
+use clap::error::ErrorKind;
+use clap::{ArgGroup, CommandFactory, Parser, ValueEnum};
+#[cfg(feature = "http")]
+use file_watcher::HttpMethod;
+use file_watcher::{
+    acquire_lock, clean_locks, Checksum, default_lock_dir, is_excluded, is_pattern, is_url, lock_dir, lock_path, normalize_path, remove_lock_file, resolve_all,
+    resolve_regex, split_regex, ChangeSignal, FileWatcher, Format, LockOptions, MatchPolicy, Mode, TimeField, WatchError, WatchOptions,
+};
+use env_logger::fmt::style::{AnsiColor, Style};
+use env_logger::fmt::WriteStyle;
+use log::{debug, error, info, warn, Level, LevelFilter};
 use regex::Regex;
+use serde_json::json;
+use std::collections::HashSet;
 use std::env;
 use std::fs::{self, File, OpenOptions};
-use std::thread::sleep;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// exit codes seen by the shell, 0 on success; with --exec a failing
+// command's own exit code is passed through instead
+
+const RET_CANNOT_LOCK: i32 = 1; // lock dir or lock file unusable, or held by another watcher
+const RET_IS_DIR: i32 = 2; // watched path is a directory (not one with --dir)
+const RET_FILE_MISSING: i32 = 3; // file went missing while waiting for an update
+const RET_TIMEOUT: i32 = 4; // --timeout elapsed
+const RET_INTERRUPTED: i32 = 5; // SIGINT/SIGTERM
+const RET_EXEC_FAILED: i32 = 6; // --exec command could not be spawned
+const RET_CHECKSUM_MISMATCH: i32 = 7; // --expect-sha256 still did not match at --timeout
+const RET_STDIN_FAILED: i32 = 8; // --stdin could not be read or gave no filenames
+const RET_INVALID_TIME: i32 = 9; // mod time unreadable or before the epoch
+const RET_PERMISSION: i32 = 10; // watched file exists but may not be read
+const RET_USAGE: i32 = 64; // invalid arguments or config file (EX_USAGE), not 2 like clap, that is RET_IS_DIR
 
-const RET_CANNOT_LOCK: i32 = 1;
-const RET_IS_DIR: i32 = 2;
-const WAIT_TIME: u64 = 10; // interval for file check in seconds
-const RET_FILE_MISSING: i32 = 3;
+// every option but the one-off --verbose, --dry-run and --clean-locks can
+// also come from a FILEWATCHER_<OPTION> env var or the --config file (a new
+// one gets its env var too), a flag on the command line wins over the env
+// var, which wins over the config file, which wins over the default; options
+// that contradict each other are rejected by clap before anything is waited
+// for, see MODES
 
+const MODES: &str = "\
+Modes:
+  By default the files are waited for to appear. --update, --until-gone, --stream and --tail-match
+  wait for something else instead and exclude each other, --dir waits for a directory (also to be
+  gone with --until-gone) and --dir-count and --dir-empty for what is in it.
+  Options starting with \"In update mode\" need --update. --min-size, --exact-size, --non-empty,
+  --count, --content-match, --wait-complete, --expect-sha256, --equals and --new-only only apply to
+  files appearing.";
 
 #[derive(Parser)]
-#[command(version, about, long_about=None)]
+#[command(version, about, long_about=None, args_override_self = true, after_help = MODES)]
+// a requires is dropped when an arg conflicting with the required one is
+// given, so the "In update mode" options have to conflict with the other
+// modes themselves, or --dir --watch would slip through
+#[command(group(ArgGroup::new("update_options").multiple(true).conflicts_with_all(["until_gone", "dir", "dir_entries", "stream", "tail_match"])))]
+#[command(group(ArgGroup::new("dir_entry_options").multiple(true).conflicts_with_all(["until_gone", "update", "stream", "tail_match"])))]
 struct Args {
-    #[arg(short, long)]
-    filename: String,
+    /// File to wait for, can be given multiple times or as a comma separated list like 'a.txt,/logs/*.log',
+    /// a literal comma in a name is written as \,
+    #[arg(short, long, required_unless_present_any = ["stdin", "clean_locks"], env = "FILEWATCHER_FILENAME")]
+    filename: Vec<String>,
+
+    /// Also read files to wait for from stdin, one per line, # starts a comment line
+    #[arg(long, env = "FILEWATCHER_STDIN", value_parser = BoolishValueParser::new())]
+    stdin: bool,
+
+    /// Only log errors, and print the resolved path to stdout like --print-path
+    #[arg(short, long, conflicts_with = "verbose", env = "FILEWATCHER_QUIET", value_parser = BoolishValueParser::new())]
+    quiet: bool,
+
+    /// Print the resolved path of each ready file to stdout before exiting
+    #[arg(long, env = "FILEWATCHER_PRINT_PATH", value_parser = BoolishValueParser::new())]
+    print_path: bool,
+
+    /// Log more, -v for debug and -vv for trace output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Read defaults for the other options from this TOML file, e.g. interval = "30s"
+    #[arg(long, env = "FILEWATCHER_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Also append the log output to this file
+    #[arg(long, env = "FILEWATCHER_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Color the log output, auto colors it when stderr is a terminal and NO_COLOR is not set
+    #[arg(long, value_enum, default_value_t = Color::Auto, env = "FILEWATCHER_COLOR")]
+    color: Color,
+
+    /// How to combine multiple files
+    #[arg(short, long, value_enum, default_value_t = Mode::All, env = "FILEWATCHER_MODE")]
+    mode: Mode,
 
     /// Use if needed to wait for file to be updated
-    #[arg(short, long)]
+    #[arg(short, long, env = "FILEWATCHER_UPDATE", value_parser = BoolishValueParser::new())]
     update: bool,
+
+    /// Wait for the file to be removed, with wildcards until nothing matches
+    #[arg(long, conflicts_with = "update", env = "FILEWATCHER_UNTIL_GONE", value_parser = BoolishValueParser::new())]
+    until_gone: bool,
+
+    /// Interval between file checks, e.g. 500ms, 2s, 1m
+    #[arg(short, long, default_value = "10s", value_parser = parse_duration, env = "FILEWATCHER_INTERVAL")]
+    interval: Duration,
+
+    /// Give up after waiting this long, e.g. 30s, 5m, 2h
+    #[arg(short, long, value_parser = parse_duration, env = "FILEWATCHER_TIMEOUT")]
+    timeout: Option<Duration>,
+
+    /// Give up after checking this many times, exits like --timeout
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "FILEWATCHER_MAX_CHECKS")]
+    max_checks: Option<u64>,
+
+    /// Log that the watcher is still waiting every this many checks, 0 disables it
+    #[arg(long, default_value_t = 6, env = "FILEWATCHER_HEARTBEAT")]
+    heartbeat: u64,
+
+    /// In update mode, wait until the file is newer than this reference file, like make
+    #[arg(long, requires = "update", group = "update_options", conflicts_with = "by_content", env = "FILEWATCHER_NEWER_THAN")]
+    newer_than: Option<String>,
+
+    /// In update mode, wait until the file was modified after this time, RFC 3339 or Unix seconds
+    #[arg(long, value_parser = parse_timestamp, requires = "update", group = "update_options", conflicts_with_all = ["by_content", "newer_than"], env = "FILEWATCHER_SINCE")]
+    since: Option<u64>,
+
+    /// Timestamp update mode compares; ctime is Unix only (mtime elsewhere), atime may be
+    /// coarse or frozen on relatime/noatime mounts
+    #[arg(long, value_enum, default_value_t = TimeField::Mtime, env = "FILEWATCHER_TIME_FIELD")]
+    time_field: TimeField,
+
+    /// In update mode, the changes that count as an update, comma separated, e.g. --detect inode to only fire once the
+    /// file is replaced (editors saving by rename, log rotation)
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [ChangeSignal::Mtime, ChangeSignal::Size, ChangeSignal::Inode], requires = "update", group = "update_options", conflicts_with_all = ["by_content", "newer_than", "since", "on_shrink"], env = "FILEWATCHER_DETECT")]
+    detect: Vec<ChangeSignal>,
+
+    /// Watch symlinks themselves, so repointing one counts as an update
+    #[arg(long, env = "FILEWATCHER_NO_FOLLOW", value_parser = BoolishValueParser::new())]
+    no_follow: bool,
+
+    /// In update mode, compare the --checksum of the content instead of the mod time
+    #[arg(long, requires = "update", group = "update_options", env = "FILEWATCHER_BY_CONTENT", value_parser = BoolishValueParser::new())]
+    by_content: bool,
+
+    /// In update mode, ignore a file that was only touched, its size or content has to change too
+    #[arg(long, requires = "update", group = "update_options", conflicts_with_all = ["by_content", "newer_than", "since"], env = "FILEWATCHER_IGNORE_TOUCH", value_parser = BoolishValueParser::new())]
+    ignore_touch: bool,
+
+    /// In update mode, wait until the file was updated this many times; changes closer together than one check count once
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), requires = "update", group = "update_options", conflicts_with_all = ["newer_than", "since"], env = "FILEWATCHER_UPDATES")]
+    updates: Option<u64>,
+
+    /// In update mode, only count the file getting smaller than it was, e.g. truncated or replaced by log rotation
+    #[arg(long, requires = "update", group = "update_options", conflicts_with_all = ["by_content", "newer_than", "since", "ignore_touch"], env = "FILEWATCHER_ON_SHRINK", value_parser = BoolishValueParser::new())]
+    on_shrink: bool,
+
+    /// In update mode, also wait until the file stopped changing for this long, e.g. 30s
+    #[arg(long, value_parser = parse_duration, requires = "update", group = "update_options", env = "FILEWATCHER_STABLE")]
+    stable: Option<Duration>,
+
+    /// With --update, once the file changed wait until it saw no further change for this long, e.g. 500ms, so a burst of
+    /// writes is one update (and one --exec); like --stable, but checked within the window rather than every --interval
+    #[arg(long, value_parser = parse_duration, requires = "update", group = "update_options", conflicts_with = "stable", env = "FILEWATCHER_DEBOUNCE")]
+    debounce: Option<Duration>,
+
+    /// In update mode, keep waiting this long for a file that disappears to come back, e.g. 5s
+    #[arg(long, value_parser = parse_duration, requires = "update", group = "update_options", conflicts_with_all = ["newer_than", "since"], env = "FILEWATCHER_TOLERATE_MISSING")]
+    tolerate_missing: Option<Duration>,
+
+    /// Which file a wildcard or regex resolves to when several match
+    #[arg(long = "match", value_enum, default_value_t = MatchPolicy::Newest, env = "FILEWATCHER_MATCH")]
+    match_policy: MatchPolicy,
+
+    /// With --update, keep running until interrupted: after every update (and --exec) take a new baseline and wait again,
+    /// so what --exec writes to the file does not trigger it again
+    #[arg(long, requires = "update", group = "update_options", env = "FILEWATCHER_WATCH", value_parser = BoolishValueParser::new())]
+    watch: bool,
+
+    /// Keep running and print a JSON line for every new matching file until interrupted
+    #[arg(long, conflicts_with_all = ["update", "until_gone", "exec", "count"], env = "FILEWATCHER_STREAM", value_parser = BoolishValueParser::new())]
+    stream: bool,
+
+    /// Match the filename part of --filename as a regular expression, e.g. 'backups/backup-\d{8}\.tar\.gz'
+    #[arg(long, env = "FILEWATCHER_REGEX", value_parser = BoolishValueParser::new())]
+    regex: bool,
+
+    /// Wait for the path to exist as a directory instead of a file
+    #[arg(long, env = "FILEWATCHER_DIR", value_parser = BoolishValueParser::new(), conflicts_with_all = ["update", "stream", "wait_complete", "expect_sha256", "equals", "min_size", "exact_size", "non_empty", "content_match"])]
+    dir: bool,
+
+    /// Wait until the directory has at least this many entries, implies --dir
+    #[arg(long, group = "dir_entries", env = "FILEWATCHER_DIR_COUNT", conflicts_with_all = ["until_gone", "update", "stream", "tail_match"])]
+    dir_count: Option<u64>,
+
+    /// Wait until the directory has no entries left, implies --dir
+    #[arg(long, group = "dir_entries", env = "FILEWATCHER_DIR_EMPTY", value_parser = BoolishValueParser::new(), conflicts_with_all = ["until_gone", "update", "stream", "tail_match"])]
+    dir_empty: bool,
+
+    /// What --dir-empty does when the directory does not exist yet
+    #[arg(long, value_enum, default_value_t = DirMissing::Wait, requires = "dir_empty", group = "dir_entry_options", env = "FILEWATCHER_DIR_MISSING")]
+    dir_missing: DirMissing,
+
+    /// Only count directory entries whose name matches this glob, e.g. '*.csv'
+    #[arg(long, value_parser = parse_glob, requires = "dir_entries", group = "dir_entry_options", env = "FILEWATCHER_DIR_GLOB")]
+    dir_glob: Option<glob::Pattern>,
+
+    /// Do not count directory entries whose name starts with a dot
+    #[arg(long, requires = "dir_entries", group = "dir_entry_options", env = "FILEWATCHER_IGNORE_HIDDEN", value_parser = BoolishValueParser::new())]
+    ignore_hidden: bool,
+
+    /// Skip matches of a wildcard or regex whose name matches this glob, e.g. 'out-tmp*.csv', can be given multiple times
+    #[arg(long, value_parser = parse_glob, env = "FILEWATCHER_EXCLUDE")]
+    exclude: Vec<glob::Pattern>,
+
+    /// Only fire for files created after the watch started, implies --print-path
+    #[arg(long, conflicts_with_all = ["update", "until_gone", "tail_match"], env = "FILEWATCHER_NEW_ONLY", value_parser = BoolishValueParser::new())]
+    new_only: bool,
+
+    /// Only treat the file as ready once it has at least this size, e.g. 512, 10K, 10M, 1G
+    #[arg(long, value_parser = parse_size, conflicts_with_all = ["update", "until_gone", "tail_match"], env = "FILEWATCHER_MIN_SIZE")]
+    min_size: Option<u64>,
+
+    /// Only treat the file as ready once it has exactly this size, e.g. 4G; a file grown past it is waited on as well, being more likely broken than done
+    #[arg(long, value_parser = parse_size, conflicts_with_all = ["min_size", "non_empty", "update", "until_gone", "tail_match"], env = "FILEWATCHER_EXACT_SIZE")]
+    exact_size: Option<u64>,
+
+    /// With --exact-size, treat a file grown past the size as ready too
+    #[arg(long, requires = "exact_size", conflicts_with_all = ["dir", "update", "until_gone", "tail_match"], env = "FILEWATCHER_OVERSIZE_READY", value_parser = BoolishValueParser::new())]
+    oversize_ready: bool,
+
+    /// Size the file will end up at, for the progress bar of its size (which --min-size and --exact-size also show)
+    #[cfg(feature = "progress")]
+    #[arg(long, value_parser = parse_size, env = "FILEWATCHER_EXPECTED_SIZE")]
+    expected_size: Option<u64>,
+
+    /// Only treat the file as ready once it is not empty, like --min-size 1
+    #[arg(long, conflicts_with_all = ["min_size", "update", "until_gone", "tail_match"], env = "FILEWATCHER_NON_EMPTY", value_parser = BoolishValueParser::new())]
+    non_empty: bool,
+
+    /// With a wildcard, wait until at least this many files match, all of them count as ready
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["update", "until_gone"], env = "FILEWATCHER_COUNT")]
+    count: Option<u64>,
+
+    /// Only treat the file as ready once its content matches this regex
+    #[arg(long, value_parser = parse_regex, conflicts_with_all = ["update", "until_gone"], env = "FILEWATCHER_CONTENT_MATCH")]
+    content_match: Option<Regex>,
+
+    /// Wait for a line matching this regex to be appended to the file, like tail -f; a rotated or truncated file is read from the start
+    #[arg(long, value_parser = parse_regex, conflicts_with_all = ["update", "until_gone", "stream", "dir", "count", "content_match", "wait_complete", "expect_sha256", "equals"], env = "FILEWATCHER_TAIL_MATCH")]
+    tail_match: Option<Regex>,
+
+    /// Read at most this much of the file for --content-match (and of a line for --tail-match), e.g. 64K
+    #[arg(long, default_value = "1M", value_parser = parse_size, env = "FILEWATCHER_CONTENT_MAX_BYTES")]
+    content_max_bytes: u64,
+
+    /// Once the file appeared, wait until its size stayed the same for this long, e.g. 30s
+    #[arg(long, value_parser = parse_duration, conflicts_with_all = ["update", "until_gone"], env = "FILEWATCHER_WAIT_COMPLETE")]
+    wait_complete: Option<Duration>,
+
+    /// Once the file appeared, also wait until its --checksum (SHA-256 by default) matches this hex digest
+    #[arg(long, visible_alias = "expect-checksum", value_parser = parse_hex_digest, conflicts_with_all = ["update", "until_gone"], env = "FILEWATCHER_EXPECT_SHA256")]
+    expect_sha256: Option<String>,
+
+    /// Once the file appeared, also wait until its content is the same as this file's, e.g. to see a copy finished
+    #[arg(long, conflicts_with_all = ["update", "until_gone", "stream"], env = "FILEWATCHER_EQUALS")]
+    equals: Option<String>,
+
+    /// Digest --expect-sha256, --equals, --by-content and --ignore-touch use
+    #[arg(long, value_enum, default_value_t = Checksum::Sha256, env = "FILEWATCHER_CHECKSUM")]
+    checksum: Checksum,
+
+    /// Multiply the interval by this factor after every check no event cut short, 1.0 disables backoff
+    #[arg(long, default_value_t = 1.0, value_parser = parse_backoff, env = "FILEWATCHER_BACKOFF")]
+    backoff: f64,
+
+    /// Randomize every sleep by up to this fraction of it either way, e.g. 0.2, so many watchers do not poll in lockstep
+    #[arg(long, default_value_t = 0.0, value_parser = parse_jitter, env = "FILEWATCHER_JITTER")]
+    jitter: f64,
+
+    /// Upper bound for the interval when backing off, e.g. 5m, 1h when not given
+    #[arg(long, value_parser = parse_duration, env = "FILEWATCHER_MAX_INTERVAL")]
+    max_interval: Option<Duration>,
+
+    /// Command to run once the wait succeeded, once per file with {} (the resolved path), {dir}, {base} or {ext} in it
+    #[arg(short, long, env = "FILEWATCHER_EXEC")]
+    exec: Option<String>,
+
+    /// Run --exec through sh -c (cmd /C on Windows) instead of directly, placeholders are not quoted
+    #[arg(long, requires = "exec", conflicts_with = "stream", env = "FILEWATCHER_EXEC_SHELL", value_parser = BoolishValueParser::new())]
+    exec_shell: bool,
+
+    /// Output format, json prints an event object per line to stdout
+    #[arg(long, value_enum, default_value_t = Format::Text, env = "FILEWATCHER_FORMAT")]
+    format: Format,
+
+    /// Also print a summary of the wait to stdout in this format, it is always logged
+    #[arg(long, value_enum, env = "FILEWATCHER_SUMMARY")]
+    summary: Option<Format>,
+
+    /// Exit code to use when the timeout elapses
+    #[arg(long, default_value_t = RET_TIMEOUT, value_parser = clap::value_parser!(i32).range(1..=255), env = "FILEWATCHER_EXIT_ON_TIMEOUT")]
+    exit_on_timeout: i32,
+
+    /// Exit code to use when the file goes missing while waiting for an update
+    #[arg(long, default_value_t = RET_FILE_MISSING, value_parser = clap::value_parser!(i32).range(1..=255), env = "FILEWATCHER_EXIT_ON_MISSING")]
+    exit_on_missing: i32,
+
+    /// Exit code to use when a watched file may not be read
+    #[arg(long, default_value_t = RET_PERMISSION, value_parser = clap::value_parser!(i32).range(1..=255), env = "FILEWATCHER_EXIT_ON_PERMISSION")]
+    exit_on_permission: i32,
+
+    /// Keep waiting when a watched file may not be read, the permissions may still be fixed
+    #[arg(long, env = "FILEWATCHER_RETRY_PERMISSION", value_parser = BoolishValueParser::new())]
+    retry_permission: bool,
+
+    /// URL to POST a JSON summary to once the wait succeeded
+    #[cfg(feature = "webhook")]
+    #[arg(long, env = "FILEWATCHER_WEBHOOK")]
+    webhook: Option<String>,
+
+    /// Give up delivering the webhook after this long
+    #[cfg(feature = "webhook")]
+    #[arg(long, default_value = "10s", value_parser = parse_duration, env = "FILEWATCHER_WEBHOOK_TIMEOUT")]
+    webhook_timeout: Duration,
+
+    /// Request http(s) targets with this method, any 2xx answer means available
+    #[cfg(feature = "http")]
+    #[arg(long, value_enum, default_value_t = HttpMethod::Head, env = "FILEWATCHER_METHOD")]
+    method: HttpMethod,
+
+    /// Only treat an s3:// target as ready once its ETag is this one
+    #[cfg(feature = "s3")]
+    #[arg(long, env = "FILEWATCHER_ETAG")]
+    etag: Option<String>,
+
+    /// Show a desktop notification once the wait succeeded
+    #[cfg(feature = "desktop")]
+    #[arg(long, env = "FILEWATCHER_NOTIFY", value_parser = BoolishValueParser::new())]
+    notify: bool,
+
+    /// With --update, compare against the state this file kept from the last run, so a change in between counts right away, and keep the current one in it when done
+    #[arg(long, requires = "update", group = "update_options", conflicts_with_all = ["newer_than", "since"], env = "FILEWATCHER_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Keep metrics about the wait in this file for the node exporter textfile collector, e.g. /var/lib/node_exporter/filewatcher.prom
+    #[arg(long, env = "FILEWATCHER_METRICS_FILE")]
+    metrics_file: Option<PathBuf>,
+
+    /// Directory for the lock files, defaults to $XDG_RUNTIME_DIR/filewatcher, then $XDG_STATE_HOME, ~/.local/state and ~
+    #[arg(long, env = "FILEWATCHER_LOCK_DIR")]
+    lock_dir: Option<PathBuf>,
+
+    /// If another watcher holds the lock, retry for this long instead of failing, e.g. 5m
+    #[arg(long, value_parser = parse_duration, conflicts_with = "no_lock", env = "FILEWATCHER_WAIT_FOR_LOCK")]
+    wait_for_lock: Option<Duration>,
+
+    /// Take a shared lock, so any number of --shared watchers can wait on the same file
+    #[arg(long, conflicts_with = "no_lock", env = "FILEWATCHER_SHARED", value_parser = BoolishValueParser::new())]
+    shared: bool,
+
+    /// Show what the files resolve to and which lock files would be used, then exit
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Skip the lock files, nothing stops a second watcher on the same file then
+    #[arg(long, conflicts_with = "clean_locks", env = "FILEWATCHER_NO_LOCK", value_parser = BoolishValueParser::new())]
+    no_lock: bool,
+
+    /// Remove lock files left behind by killed watchers, then exit unless files are given
+    #[arg(long)]
+    clean_locks: bool,
+
+    /// Poll at every interval instead of using filesystem events, e.g. for network filesystems
+    #[arg(long, env = "FILEWATCHER_POLL", value_parser = BoolishValueParser::new())]
+    poll: bool,
 }
 
-fn main() -> Result<(), i32> {
-    let args = Args::parse();
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Color {
+    /// Only when stderr is a terminal and NO_COLOR is not set
+    Auto,
+    Always,
+    Never,
+}
 
-    let filename = args.filename;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DirMissing {
+    /// Wait for the directory to be created first
+    Wait,
+    /// Fail right away with the missing file exit code
+    Error,
+}
 
-    env_logger::init();
+// a usage error is printed like clap does, --help and --version are no
+// errors and exit 0
 
-    // Create and lock the stale file
+pub(crate) fn exit_usage(error: clap::Error) -> ! {
+    if !error.use_stderr() {
+        error.exit();
+    }
+    let _ = error.print();
+    std::process::exit(RET_USAGE)
+}
 
-    let (lock, lock_file) = create_lock_file(&filename);
-    match lock.try_lock_exclusive() {
-        Ok(()) => {
-            info!("Stale file generated '{}'", &lock_file);
+fn main() {
+    // run() has dropped the lock guards by the time it returns, so exiting
+    // right away cannot leave a lock file behind
+    let code = match run() {
+        Ok(()) => 0,
+        Err(code) => code,
+    };
+    std::process::exit(code);
+}
 
-            if args.update {
-                match wait_for_file_update(&filename) {
-                    Ok(()) => {
-                        remove_lock_file(&lock_file);
-                        return Ok(());
-                    }
+fn run() -> Result<(), i32> {
+    let args = Args::try_parse_from(config::args_with_config()).unwrap_or_else(|e| exit_usage(e));
+    init_logger(args.quiet, args.verbose, args.log_file.as_deref(), args.color);
 
-                    Err(ret) => return Err(ret),
-                }
-            } else {
-                wait_for_file(&filename);
+    let mut filenames: Vec<String> = args.filename.iter().flat_map(|list| split_filenames(list)).collect();
+    if filenames.is_empty() && !args.filename.is_empty() {
+        exit_usage(Args::command().error(ErrorKind::InvalidValue, "--filename holds no filenames"));
+    }
+    if args.stdin {
+        filenames.extend(read_filenames(io::stdin().lock())?);
+        if filenames.is_empty() {
+            error!("No filenames given on stdin");
+            return Err(RET_STDIN_FAILED);
+        }
+    }
+    for filename in &mut filenames {
+        *filename = expand_filename(filename).unwrap_or_else(|e| {
+            exit_usage(Args::command().error(ErrorKind::ValueValidation, format!("cannot expand '{}': {}", filename, e)))
+        });
+    }
+    // the regex part of a --regex path is no path, only its directory is normalized
+    for filename in &mut filenames {
+        *filename = match args.regex {
+            true if !filename.contains(std::path::is_separator) => continue,
+            true => {
+                let (dir, name) = split_regex(filename);
+                Path::new(&normalize_path(&dir.to_string_lossy())).join(name).to_string_lossy().into_owned()
+            }
+            false => normalize_path(filename),
+        };
+    }
+    let mut seen = HashSet::new();
+    filenames.retain(|f| seen.insert(f.clone()));
+    if args.regex {
+        check_regexes(&filenames);
+    }
+    let appearance = !(args.update || args.until_gone || args.dir || args.stream || args.tail_match.is_some())
+        && args.wait_complete.is_none()
+        && args.expect_sha256.is_none()
+        && args.equals.is_none();
+    check_urls(&filenames, appearance);
+    if let Some(expected) = &args.expect_sha256
+        && expected.len() != args.checksum.hex_len()
+    {
+        let msg = format!(
+            "expected digest has {} hex digits, {} needs {}",
+            expected.len(),
+            args.checksum,
+            args.checksum.hex_len()
+        );
+        exit_usage(Args::command().error(ErrorKind::ValueValidation, msg));
+    }
+
+    // Create and lock the stale files, one per watched file
+
+    let codes = ExitCodes {
+        timeout: args.exit_on_timeout,
+        file_missing: args.exit_on_missing,
+        permission: args.exit_on_permission,
+    };
+    let exit_code = |err| codes.of(err);
+
+    if args.dry_run {
+        let lock_dir = match (args.no_lock, args.lock_dir) {
+            (true, _) => None,
+            (false, Some(dir)) => Some(dir),
+            (false, None) => Some(default_lock_dir(false).map_err(exit_code)?),
+        };
+        dry_run(&filenames, args.regex, &args.exclude, lock_dir.as_deref());
+        return Ok(());
+    }
+
+    let mut locks = Vec::new();
+    if args.no_lock {
+        debug!("Running without lock files");
+    } else {
+        let lock_dir = lock_dir(args.lock_dir).map_err(exit_code)?;
+        if args.clean_locks {
+            let removed = clean_locks(&lock_dir);
+            info!("Removed {} stale lock file(s) from '{}'", removed, lock_dir.display());
+            if filenames.is_empty() {
+                return Ok(());
+            }
+        }
+        let lock_options = LockOptions {
+            format: args.format,
+            wait: args.wait_for_lock,
+            shared: args.shared,
+        };
+        for filename in &filenames {
+            locks.push(acquire_lock(filename, &lock_dir, &lock_options).map_err(exit_code)?);
+        }
+    }
+    // a shared lock file may still be in use by other watchers, it is
+    // left for --clean-locks when interrupted
+    let lock_files = Arc::new(Mutex::new(
+        locks
+            .iter()
+            .filter(|lock| !lock.is_shared())
+            .map(|lock| lock.path().to_path_buf())
+            .collect::<Vec<_>>(),
+    ));
+    // an interrupt is how a stream or watch normally ends
+    let interrupted = if args.stream || args.watch { 0 } else { RET_INTERRUPTED };
+    install_signal_handler(Arc::clone(&lock_files), interrupted);
+
+    let actions = Actions {
+        print_path: args.print_path || args.quiet || args.new_only,
+        format: args.format,
+        exec: args.exec.as_deref(),
+        exec_shell: args.exec_shell,
+        #[cfg(feature = "webhook")]
+        webhook: args.webhook.as_deref().map(|url| (url, args.webhook_timeout)),
+        #[cfg(feature = "desktop")]
+        notify: args.notify,
+    };
+    let options = WatchOptions {
+        update: args.update,
+        until_gone: args.until_gone,
+        mode: args.mode,
+        interval: args.interval,
+        timeout: args.timeout,
+        max_checks: args.max_checks,
+        heartbeat: args.heartbeat,
+        backoff: args.backoff,
+        jitter: args.jitter,
+        max_interval: args.max_interval,
+        poll: args.poll,
+        stable: args.stable.or(args.debounce),
+        tolerate_missing: args.tolerate_missing,
+        min_size: args.min_size.or(args.non_empty.then_some(1)),
+        exact_size: args.exact_size,
+        oversize_ready: args.oversize_ready,
+        count: args.count,
+        content_match: args.content_match,
+        content_max_bytes: args.content_max_bytes,
+        tail_match: args.tail_match,
+        wait_complete: args.wait_complete,
+        expect_sha256: args.expect_sha256,
+        equals: args.equals,
+        checksum: args.checksum,
+        match_policy: args.match_policy,
+        regex: args.regex,
+        dir: args.dir || args.dir_count.is_some() || args.dir_empty,
+        dir_count: args.dir_count,
+        dir_empty: args.dir_empty,
+        dir_must_exist: args.dir_empty && args.dir_missing == DirMissing::Error,
+        dir_glob: args.dir_glob,
+        ignore_hidden: args.ignore_hidden,
+        exclude: args.exclude,
+        new_only: args.new_only,
+        newer_than: args.newer_than,
+        since: args.since,
+        #[cfg(feature = "http")]
+        http_method: args.method,
+        #[cfg(feature = "s3")]
+        s3_etag: args.etag,
+        #[cfg(feature = "progress")]
+        progress: !args.quiet && args.format == Format::Text,
+        #[cfg(feature = "progress")]
+        expected_size: args.expected_size,
+        metrics_file: args.metrics_file,
+        log_file: args.log_file.clone(),
+        state_file: args.state_file,
+        time_field: args.time_field,
+        detect: args.detect,
+        no_follow: args.no_follow,
+        by_content: args.by_content,
+        ignore_touch: args.ignore_touch,
+        updates: args.updates,
+        on_shrink: args.on_shrink,
+        retry_permission: args.retry_permission,
+        format: args.format,
+    };
+    let event = if args.stream {
+        "streamed"
+    } else if args.update {
+        "updated"
+    } else if args.until_gone {
+        "removed"
+    } else if options.tail_match.is_some() {
+        "matched"
+    } else {
+        "appeared"
+    };
+    // the locks are taken, from here on it is only waiting
+    #[cfg(feature = "systemd")]
+    file_watcher::notify_ready(&options);
+    let start = Instant::now();
+    let urls: Vec<String> = filenames.iter().filter(|f| is_url(f)).cloned().collect();
+    let watcher = FileWatcher::with_paths(filenames).options(options);
+    let res = loop {
+        let round = Instant::now();
+        let res = match args.stream {
+            true => watcher.stream(print_streamed).map(|()| Vec::new()),
+            false => watcher.wait_all(),
+        };
+        summarize(&res, event, round.elapsed(), watcher.checks(), &remote_details(&urls), args.summary);
+        match res {
+            // with --watch a failing --exec is logged, the next update may go better
+            Ok(paths) if args.watch => {
+                let _ = after_wait(&actions, &paths, event, round.elapsed());
+                info!("Watching for the next update");
             }
+            res => break res,
         }
-        Err(e) => {
-            error!("Cannot obtain lock on '{}': {}", &lock_file, e);
-            return Err(RET_CANNOT_LOCK);
+    };
+
+    // keep the signal handler out while the guards clean up
+    let mut lock_files = lock_files.lock().unwrap();
+    lock_files.clear();
+    drop(locks);
+    drop(lock_files);
+
+    let paths = res.map_err(exit_code)?;
+    if args.stream {
+        return Ok(());
+    }
+    after_wait(&actions, &paths, event, start.elapsed())
+}
+
+// what is done with the files once the wait succeeded, borrowed from the
+// args the watch options did not take
+
+struct Actions<'a> {
+    print_path: bool,
+    format: Format,
+    exec: Option<&'a str>,
+    exec_shell: bool,
+    #[cfg(feature = "webhook")]
+    webhook: Option<(&'a str, Duration)>,
+    #[cfg(feature = "desktop")]
+    notify: bool,
+}
+
+#[cfg_attr(not(feature = "webhook"), allow(unused_variables))]
+fn after_wait(actions: &Actions, paths: &[PathBuf], event: &str, elapsed: Duration) -> Result<(), i32> {
+    // stdout carries just the paths for $(...), the logs go to stderr; the
+    // JSON events already name the files
+    if actions.print_path && actions.format == Format::Text {
+        for path in paths {
+            println!("{}", path.display());
+        }
+    }
+
+    #[cfg(feature = "webhook")]
+    if let Some((url, timeout)) = actions.webhook {
+        for path in paths {
+            file_watcher::send_webhook(url, path, event, elapsed, timeout);
+        }
+    }
+
+    #[cfg(feature = "desktop")]
+    if actions.notify {
+        for path in paths {
+            file_watcher::notify_desktop(path, elapsed);
         }
     }
 
-    remove_lock_file(&lock_file);
+    if let Some(command) = actions.exec {
+        run_command(command, paths, actions.exec_shell)?;
+    }
     Ok(())
 }
 
-fn wait_for_file_update(filename: &String) -> Result<(), i32> {
-    if fs::exists(&filename).unwrap() {
-        let last_mod = get_last_mod(&filename).unwrap();
+// a --stream line for a file that appeared
+
+fn print_streamed(path: &Path) {
+    let metadata = fs::metadata(path).ok();
+    let line = json!({
+        "path": path.display().to_string(),
+        "size": metadata.as_ref().map(|m| m.len()),
+        "mtime": metadata
+            .and_then(|m| m.modified().ok())
+            .map(|time| DateTime::<Utc>::from(time).to_rfc3339()),
+    });
+    println!("{}", line);
+}
 
-        //let mut latest_mod: u64;
+// one line on how the wait went, --summary json also prints it to stdout
 
-        loop {
-            let last_mod_res = get_last_mod(filename);
-            match last_mod_res {
-                Ok(latest_mod) => {
-                    if last_mod < latest_mod {
-                        info!("File updated, exiting...");
-                        return Ok(());
-                    }
-                    sleep(Duration::from_secs(WAIT_TIME));
+fn summarize(
+    res: &Result<Vec<PathBuf>, WatchError>,
+    event: &str,
+    elapsed: Duration,
+    checks: u64,
+    remote: &serde_json::Map<String, serde_json::Value>,
+    format: Option<Format>,
+) {
+    let (outcome, paths) = match res {
+        Ok(paths) => (event.to_string(), paths.as_slice()),
+        Err(err) => (err.to_string(), &[][..]),
+    };
+    let names: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+    let on = match names.is_empty() {
+        true => String::new(),
+        false => format!(" '{}'", names.join("', '")),
+    };
+    info!("Finished after {:?} and {} check(s): {}{}", elapsed, checks, outcome, on);
+    for (target, details) in remote {
+        info!("Last seen of '{}': {}", target, details);
+    }
+    if format == Some(Format::Json) {
+        let mut summary = json!({
+            "elapsed_secs": elapsed.as_secs_f64(),
+            "checks": checks,
+            "paths": names,
+            "outcome": outcome,
+            "success": res.is_ok(),
+        });
+        if !remote.is_empty() {
+            summary["remote"] = serde_json::Value::Object(remote.clone());
+        }
+        println!("{}", summary);
+    }
+}
+
+// what the summary reports about remote targets, what they looked like at
+// the last check
+
+fn remote_details(urls: &[String]) -> serde_json::Map<String, serde_json::Value> {
+    urls.iter()
+        .filter_map(|url| Some((url.clone(), remote_detail(url)?)))
+        .collect()
+}
+
+#[cfg_attr(not(any(feature = "http", feature = "s3")), allow(unused_variables))]
+fn remote_detail(url: &str) -> Option<serde_json::Value> {
+    #[cfg(feature = "s3")]
+    if url.starts_with("s3://") {
+        let object = file_watcher::last_object(url).unwrap_or_default();
+        return Some(json!({ "size": object.size, "last_modified": object.last_modified, "etag": object.etag }));
+    }
+    #[cfg(feature = "http")]
+    if !url.starts_with("s3://") {
+        return Some(json!({ "status": file_watcher::last_status(url) }));
+    }
+    None
+}
+
+// URLs can only be waited for to appear, and only with the feature for
+// their scheme
+
+fn check_urls(filenames: &[String], appearance: bool) {
+    let Some(url) = filenames.iter().find(|f| is_url(f)) else {
+        return;
+    };
+    let (feature, built) = match url.starts_with("s3://") {
+        true => ("s3", cfg!(feature = "s3")),
+        false => ("http", cfg!(feature = "http")),
+    };
+    let msg = if !built {
+        format!("'{}' is an URL, but this build has no {} feature", url, feature)
+    } else if !appearance {
+        format!("'{}' is an URL, it can only be waited for to appear", url)
+    } else {
+        return;
+    };
+    exit_usage(Args::command().error(ErrorKind::ArgumentConflict, msg));
+}
+
+// a --filename may hold several comma separated paths, \, is a comma that is
+// part of one; any other \ is left alone, so Windows paths and regex escapes
+// need no doubling, and empty entries (a trailing comma) are dropped
+
+fn split_filenames(list: &str) -> Vec<String> {
+    let mut filenames = Vec::new();
+    let mut current = String::new();
+    let mut chars = list.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&',') => {
+                current.push(',');
+                chars.next();
+            }
+            ',' => filenames.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    filenames.push(current);
+    filenames.retain(|filename| !filename.is_empty());
+    filenames
+}
+
+// newline separated paths, blank lines and # comments are skipped
+
+fn read_filenames(input: impl BufRead) -> Result<Vec<String>, i32> {
+    let mut filenames = Vec::new();
+    for line in input.lines() {
+        let line = line.map_err(|e| {
+            error!("Cannot read filenames from stdin: {}", e);
+            RET_STDIN_FAILED
+        })?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            filenames.push(line.to_string());
+        }
+    }
+    Ok(filenames)
+}
+
+// shell style expansion of a leading ~ or ~user and of $VAR and ${VAR}, for
+// filenames that did not go through a shell (quoted, from stdin), an unset
+// variable is an error rather than a path that never appears
+
+fn expand_filename(filename: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = filename;
+    if let Some(tilde) = rest.strip_prefix('~') {
+        let end = tilde.find(std::path::is_separator).unwrap_or(tilde.len());
+        let (user, after) = tilde.split_at(end);
+        expanded.push_str(&home_dir(user)?);
+        rest = after;
+    }
+
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        let (name, next) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => return Err("unterminated ${".to_string()),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                after.split_at(end)
+            }
+        };
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            // not a variable, e.g. a regex anchor
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        match env::var(name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_) => return Err(format!("environment variable '{}' is not set", name)),
+        }
+        rest = next;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn home_dir(user: &str) -> Result<String, String> {
+    if user.is_empty() {
+        let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+        return env::var(var).map_err(|_| format!("cannot expand ~, {} is not set", var));
+    }
+    // no getpwnam without libc, the passwd file has the same answer for
+    // local users
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+    passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() > 5 && fields[0] == user)
+        .map(|fields| fields[5].to_string())
+        .ok_or_else(|| format!("unknown user '{}'", user))
+}
+
+// a typo in a --regex would otherwise just never match, reject it up front
+
+fn check_regexes(filenames: &[String]) {
+    for filename in filenames {
+        let (_, name) = split_regex(filename);
+        if let Err(e) = Regex::new(name) {
+            exit_usage(Args::command().error(ErrorKind::ValueValidation, format!("invalid regex '{}': {}", name, e)));
+        }
+    }
+}
+
+// what a watch would start from, without locking or waiting
+
+fn dry_run(filenames: &[String], regex: bool, excludes: &[glob::Pattern], lock_dir: Option<&Path>) {
+    for filename in filenames {
+        println!("{}", filename);
+        let matches = if regex {
+            resolve_regex(filename)
+        } else if is_pattern(filename) {
+            resolve_all(filename)
+        } else {
+            vec![PathBuf::from(filename)]
+        };
+        let matches: Vec<PathBuf> = match regex || is_pattern(filename) {
+            true => matches.into_iter().filter(|path| !is_excluded(path, excludes)).collect(),
+            false => matches,
+        };
+        if matches.is_empty() {
+            println!("  matches nothing yet");
+        }
+        for path in &matches {
+            match fs::metadata(path) {
+                Ok(metadata) => {
+                    let modified = metadata
+                        .modified()
+                        .map(|time| DateTime::<Utc>::from(time).to_rfc3339())
+                        .unwrap_or_else(|e| format!("unknown ({})", e));
+                    let kind = if metadata.is_dir() { "directory" } else { "file" };
+                    println!("  {} exists, {}, modified {}", path.display(), kind, modified);
                 }
-                Err(ret) => return Err(ret),
+                Err(_) => println!("  {} does not exist", path.display()),
             }
         }
-    } else {
-        warn!("File '{}' does not exist. Waiting...", &filename);
-        wait_for_file(filename);
-        Ok(())
+        match lock_dir {
+            Some(lock_dir) => println!("  lock file {}", lock_path(filename, lock_dir).display()),
+            None => println!("  no lock file"),
+        }
     }
 }
-//}
 
-fn wait_for_file(filepath: &String) {
-    let mut temp_filepath = filepath.clone();
-    loop {
-        if filepath.contains('*') {
-            if let Some(filename) = resolve_file_name(&filepath) {
-                temp_filepath = filename;
+// -q/-v pick the level, a RUST_LOG from the environment still wins; with
+// --log-file every line also goes to that file
+
+fn init_logger(quiet: bool, verbose: u8, log_file: Option<&Path>, color: Color) {
+    let mut builder = if env::var_os("RUST_LOG").is_some() {
+        env_logger::Builder::from_default_env()
+    } else {
+        let level = match (quiet, verbose) {
+            (true, _) => LevelFilter::Error,
+            (false, 0) => LevelFilter::Info,
+            (false, 1) => LevelFilter::Debug,
+            (false, _) => LevelFilter::Trace,
+        };
+        // keep dependencies at info at most, their debug output is noise here
+        let mut builder = env_logger::Builder::new();
+        builder
+            .filter_level(level.min(LevelFilter::Info))
+            .filter_module("file_watcher", level);
+        builder
+    };
+    builder.format(format_record).write_style(match color {
+        Color::Auto => WriteStyle::Auto,
+        Color::Always => WriteStyle::Always,
+        Color::Never => WriteStyle::Never,
+    });
+
+    // the logger has to exist to complain about the file
+    let mut open_error = None;
+    if let Some(path) = log_file {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => {
+                builder.target(env_logger::Target::Pipe(Box::new(Tee(file))));
             }
+            Err(e) => open_error = Some(e),
         }
-        if fs::exists(&temp_filepath).unwrap() {
-            info!("File '{}' is available, bye...", &temp_filepath);
-            return;
+    }
+    #[cfg(not(feature = "progress"))]
+    builder.init();
+    #[cfg(feature = "progress")]
+    {
+        let logger = builder.build();
+        log::set_max_level(logger.filter());
+        let _ = log::set_boxed_logger(Box::new(AroundProgress(logger)));
+    }
+    if let (Some(path), Some(e)) = (log_file, open_error) {
+        warn!("Cannot open log file '{}', logging to stderr only: {}", path.display(), e);
+    }
+}
+
+// the default env_logger layout with millisecond timestamps, warnings and
+// errors are yellow and red as a whole and a file becoming available green;
+// the text stays the same, without color the styles are dropped
+
+fn format_record(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let subtle = AnsiColor::BrightBlack.on_default();
+    let level = buf.default_level_style(record.level());
+    let message = match record.level() {
+        Level::Error => AnsiColor::Red.on_default(),
+        Level::Warn => AnsiColor::Yellow.on_default(),
+        Level::Info if record.args().to_string().contains("available") => AnsiColor::Green.on_default(),
+        _ => Style::new(),
+    };
+    writeln!(
+        buf,
+        "{subtle}[{subtle:#}{} {level}{:<5}{level:#} {}{subtle}]{subtle:#} {message}{}{message:#}",
+        buf.timestamp_millis(),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+// log lines are written with the progress bar out of the way
+
+#[cfg(feature = "progress")]
+struct AroundProgress(env_logger::Logger);
+
+#[cfg(feature = "progress")]
+impl log::Log for AroundProgress {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.0.matches(record) {
+            file_watcher::suspend_progress(|| self.0.log(record));
         }
+    }
 
-        sleep(Duration::from_secs(WAIT_TIME));
+    fn flush(&self) {
+        self.0.flush();
     }
 }
 
-fn get_last_mod(file: &String) -> Result<u64, i32> {
-    let metadata_res = fs::metadata(file);
-    match metadata_res {
-        Ok(metadata) => {
-            if !metadata.is_dir() {
-                let time = metadata.modified().unwrap();
-                let last_mod = get_seconds(time);
-                debug!("Duration till last mod: {}", last_mod);
-                Ok(last_mod)
-            } else {
-                warn!(
-                    "Cannot check file presence, '{}' is a directory. Exiting (retcode={})",
-                    file, RET_IS_DIR
-                );
-                Err(RET_IS_DIR)
+// log target writing to stderr and the --log-file
+
+struct Tee(File);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        self.0.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        self.0.flush()
+    }
+}
+
+// the only place library errors turn into exit codes, timeout, missing
+// file and permission codes can be remapped on the command line
+
+struct ExitCodes {
+    timeout: i32,
+    file_missing: i32,
+    permission: i32,
+}
+
+impl ExitCodes {
+    fn of(&self, err: WatchError) -> i32 {
+        debug!("Exiting: {}", err);
+        match err {
+            WatchError::CannotLock => RET_CANNOT_LOCK,
+            WatchError::IsDir | WatchError::NotDir => RET_IS_DIR,
+            WatchError::FileMissing => self.file_missing,
+            WatchError::Timeout => self.timeout,
+            WatchError::ChecksumMismatch => RET_CHECKSUM_MISMATCH,
+            WatchError::InvalidTime => RET_INVALID_TIME,
+            WatchError::PermissionDenied => self.permission,
+            // clap makes sure there is a path
+            WatchError::NoPaths => RET_USAGE,
+        }
+    }
+}
+
+// placeholders --exec fills in from a resolved file: {} the whole path,
+// {dir} the directory it is in, {base} its name and {ext} its extension
+// (without the dot, empty if it has none)
+
+const PLACEHOLDERS: [&str; 4] = ["{}", "{dir}", "{base}", "{ext}"];
+
+fn substitute(template: &str, path: &Path) -> String {
+    let part = |part: Option<&std::ffi::OsStr>| part.map(|p| p.to_string_lossy().into_owned()).unwrap_or_default();
+    // in the order of PLACEHOLDERS
+    let values = [
+        path.to_string_lossy().into_owned(),
+        path.parent().map(|d| d.to_string_lossy().into_owned()).unwrap_or_default(),
+        part(path.file_name()),
+        part(path.extension()),
+    ];
+    // one pass, a value is never searched for placeholders itself
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(c) = rest.chars().next() {
+        match PLACEHOLDERS.iter().position(|placeholder| rest.starts_with(placeholder)) {
+            Some(i) => {
+                out.push_str(&values[i]);
+                rest = &rest[PLACEHOLDERS[i].len()..];
             }
-        } 
-        Err(_) => {error!("File '{}' went missing :(, restart again if you want to wait for it's arrival", &file);
-                    return Err(RET_FILE_MISSING);
-                    }
+            None => {
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
     }
+    out
 }
 
-// get filename incase of wildcards
+// run the --exec command, once per resolved file if it has a placeholder;
+// without --exec-shell it is split into words up front and run directly, so
+// whatever is in a filename stays a single argument
 
-fn resolve_file_name(filename: &String) -> Option<String> {
-    let a = filename.rfind("/").unwrap();
-    let (path, file) = filename.split_at(a + 1);
+fn run_command(command: &str, paths: &[PathBuf], shell: bool) -> Result<(), i32> {
+    let words = match shell {
+        true => Vec::new(),
+        false => split_command(command).map_err(|e| {
+            error!("Cannot parse '{}': {} (retcode={})", command, e, RET_EXEC_FAILED);
+            RET_EXEC_FAILED
+        })?,
+    };
+    let per_file = PLACEHOLDERS.iter().any(|placeholder| command.contains(placeholder));
+    let targets: Vec<Option<&PathBuf>> = match per_file {
+        true => paths.iter().map(Some).collect(),
+        false => vec![None],
+    };
+
+    for target in targets {
+        let fill = |template: &str| match target {
+            Some(path) => substitute(template, path),
+            None => template.to_string(),
+        };
+        let mut child = if shell {
+            let command = fill(command);
+            if cfg!(windows) {
+                let mut child = Command::new("cmd");
+                child.args(["/C", &command]);
+                child
+            } else {
+                let mut child = Command::new("sh");
+                child.args(["-c", &command]);
+                child
+            }
+        } else {
+            let argv: Vec<String> = words.iter().map(|word| fill(word)).collect();
+            let Some((program, args)) = argv.split_first() else {
+                error!("Command '{}' is empty (retcode={})", command, RET_EXEC_FAILED);
+                return Err(RET_EXEC_FAILED);
+            };
+            let mut child = Command::new(program);
+            child.args(args);
+            child
+        };
+        info!("Running {:?}", child);
+        match child.status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                let code = status.code().unwrap_or(RET_EXEC_FAILED);
+                warn!("Command {:?} failed: {}", child, status);
+                return Err(code);
+            }
+            Err(e) => {
+                error!("Cannot run {:?}: {} (retcode={})", child, e, RET_EXEC_FAILED);
+                return Err(RET_EXEC_FAILED);
+            }
+        }
+    }
+    Ok(())
+}
 
-    let (part_a, part_b) = file.split_at(file.find("*").unwrap());
-    let file_len = file.len()-1;
-    let part_a_end = part_a.len()-1;
-    debug!("Search file len {}, part a end {}", file_len, part_a_end);
-    //let part_b_start: usize = part_a_end + 1;
-    //let part_b_start = part_b.len()
+// split a command line into words like a POSIX shell would, minus
+// expansions: whitespace separates words, '...' is literal, "..." allows \"
+// and \\ and a backslash outside quotes escapes the next character
 
-    for item in fs::read_dir(path).unwrap() {
-        let item = item.unwrap();
-        let buf = item.path();
-        let name = buf.file_name().unwrap().to_str().unwrap().to_string();
+fn split_command(command: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated '".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err("unterminated \"".to_string()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated \"".to_string()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("trailing backslash".to_string()),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
 
+// remove the lock files and exit on SIGINT/SIGTERM, the paths are taken
+// out so a second signal has nothing left to remove
 
-        if name.len() >= file_len{
-        debug!("Starts with {}, ends with {}, full {}", 
-            &name[0..part_a_end], &name[(name.len()-part_b.len())..], &name);
-        // find the file if exist
-        if name.starts_with(&part_a[0..part_a_end]) && name.ends_with(&part_b[1..]) {
-            let abs_file_path = path.to_owned() + &name;
-            return Some(abs_file_path);
+fn install_signal_handler(lock_files: Arc<Mutex<Vec<PathBuf>>>, code: i32) {
+    let res = ctrlc::set_handler(move || {
+        let paths = std::mem::take(&mut *lock_files.lock().unwrap());
+        if !paths.is_empty() {
+            warn!("Interrupted, cleaning up (retcode={})", code);
         }
+        for path in &paths {
+            remove_lock_file(path);
         }
+        let _ = io::stdout().flush();
+        std::process::exit(code);
+    });
+    if let Err(e) = res {
+        warn!("Cannot install signal handler, lock files may be left behind: {}", e);
     }
+}
 
-    //println!("{}, {}", path, file);
-    None
-    // file
+fn parse_backoff(input: &str) -> Result<f64, String> {
+    let factor: f64 = input
+        .parse()
+        .map_err(|_| format!("Invalid backoff factor '{}'", input))?;
+    if !(1.0..=100.0).contains(&factor) {
+        return Err(format!("Backoff factor '{}' must be between 1.0 and 100.0", input));
+    }
+    Ok(factor)
 }
 
-fn get_seconds(modified: SystemTime) -> u64 {
-    if let Ok(duration) = modified.duration_since(UNIX_EPOCH) {
-        return duration.as_secs();
-    } else {
-        println!("Error getting duration  since EPOCH");
+fn parse_jitter(input: &str) -> Result<f64, String> {
+    let fraction: f64 = input
+        .parse()
+        .map_err(|_| format!("Invalid jitter '{}'", input))?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(format!("Jitter '{}' must be between 0.0 and 1.0", input));
     }
-    1
+    Ok(fraction)
 }
 
-fn create_lock_file(filename: &String) -> (File, String) {
-    let lock_name = sanitize(filename);
-    let mut lock_path = env::var("HOME").unwrap() + "/filewatcher/";
-    if !fs::exists(&lock_path).unwrap() {
-        fs::create_dir(&lock_path).expect(&format!("Failed to create lock dir '{}'", lock_path));
+// RFC 3339 like 2024-05-01T12:00:00Z or Unix seconds, as Unix seconds
+
+fn parse_timestamp(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    if let Ok(secs) = input.parse::<u64>() {
+        return Ok(secs);
     }
-    lock_path.push_str(&lock_name);
+    let time = DateTime::parse_from_rfc3339(input).map_err(|e| {
+        format!("Invalid timestamp '{}', expected RFC 3339 or Unix seconds: {}", input, e)
+    })?;
+    u64::try_from(time.timestamp()).map_err(|_| format!("Timestamp '{}' is before 1970", input))
+}
 
-    let lock = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&lock_path)
-        .expect(&format!("Failed to open lock '{}'", &lock_path));
+fn parse_regex(input: &str) -> Result<Regex, String> {
+    Regex::new(input).map_err(|e| format!("Invalid regex '{}': {}", input, e))
+}
 
-    (lock, lock_path)
+fn parse_glob(input: &str) -> Result<glob::Pattern, String> {
+    glob::Pattern::new(input).map_err(|e| format!("Invalid glob '{}': {}", input, e))
+}
+
+// accept a digest in hex, normalized to lowercase for comparing; its length
+// is checked against --checksum once all options are known
+
+fn parse_hex_digest(input: &str) -> Result<String, String> {
+    let input = input.trim();
+    if input.is_empty() || !input.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid digest '{}', expected hex digits", input));
+    }
+    Ok(input.to_ascii_lowercase())
 }
 
-fn sanitize(input: &String) -> String {
-    let regex = Regex::new(r"[^a-zA-Z0-9]").unwrap();
-    regex.replace_all(input, "_").to_string()
+// parse human readable sizes like 512, 10K, 10M or 1G (powers of 1024)
+
+fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(split);
+
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid size '{}', expected e.g. 512, 10K, 10M, 1G", input))?;
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("Unknown size unit '{}' in '{}'", unit, input)),
+    };
+
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size '{}' is too large", input))
+}
+
+// parse human readable durations like 500ms, 2s, 1m or 1h
+
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(split);
+
+    let value: f64 = value
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}', expected e.g. 500ms, 2s, 1m", input))?;
+    if value <= 0.0 {
+        return Err(format!("Duration '{}' must be greater than zero", input));
+    }
+
+    let secs = match unit {
+        "ms" => value / 1000.0,
+        "" | "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => return Err(format!("Unknown duration unit '{}' in '{}'", unit, input)),
+    };
+
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("Invalid duration '{}': {}", input, e))
 }
 
-fn remove_lock_file(lock_file: &String) {
-    fs::remove_file(lock_file).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Args, clap::Error> {
+        Args::try_parse_from(["file_watcher", "-f", "x"].into_iter().chain(args.split_whitespace()))
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("2"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration(" 10s "), Ok(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn parse_duration_rejects_bad_input() {
+        for input in ["", "0", "0s", "-1s", "abc", "10d", "s", "1e400"] {
+            assert!(parse_duration(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("10K"), Ok(10 << 10));
+        assert_eq!(parse_size("10kb"), Ok(10 << 10));
+        assert_eq!(parse_size("10M"), Ok(10 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert_eq!(parse_size("2T"), Ok(2 << 40));
+        for input in ["", "K", "1.5K", "-1", "10X", "99999999999T"] {
+            assert!(parse_size(input).is_err(), "{}", input);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn expand_filename_home_and_variables() {
+        let home = env::var("HOME").unwrap();
+        assert_eq!(expand_filename("~/in.csv"), Ok(format!("{}/in.csv", home)));
+        assert_eq!(expand_filename("~"), Ok(home.clone()));
+        assert_eq!(expand_filename("$HOME/in.csv"), Ok(format!("{}/in.csv", home)));
+        assert_eq!(expand_filename("${HOME}x/in.csv"), Ok(format!("{}x/in.csv", home)));
+        // a $ that starts no variable stays
+        assert_eq!(expand_filename("a/b$"), Ok("a/b$".to_string()));
+        assert_eq!(expand_filename("a/$1"), Ok("a/$1".to_string()));
+        assert_eq!(expand_filename("a/~b"), Ok("a/~b".to_string()));
+    }
+
+    #[test]
+    fn expand_filename_rejects_missing_variables() {
+        let err = expand_filename("/data/$FILEWATCHER_TEST_UNSET/in.csv").unwrap_err();
+        assert!(err.contains("FILEWATCHER_TEST_UNSET"), "{}", err);
+        assert!(expand_filename("/data/${HOME").is_err());
+        assert!(expand_filename("~no-such-user-here/x").is_err());
+    }
+
+    #[test]
+    fn split_filenames_at_unescaped_commas() {
+        assert_eq!(split_filenames("a.txt,/logs/*.log"), ["a.txt", "/logs/*.log"]);
+        assert_eq!(split_filenames(r"in\,out.csv,b"), ["in,out.csv", "b"]);
+        assert_eq!(split_filenames(r"C:\data\x,a\.b"), [r"C:\data\x", r"a\.b"]);
+        assert_eq!(split_filenames("a,,b,"), ["a", "b"]);
+        assert!(split_filenames(",").is_empty());
+    }
+
+    #[test]
+    fn split_command_like_a_shell() {
+        let words = |command: &str| split_command(command).unwrap();
+        assert_eq!(words("gzip -k  {}"), ["gzip", "-k", "{}"]);
+        assert_eq!(words("echo 'a  b' \"c \\\" d\" e\\ f"), ["echo", "a  b", "c \" d", "e f"]);
+        assert_eq!(words("x '' \"\""), ["x", "", ""]);
+        assert_eq!(words("a'b'\"c\""), ["abc"]);
+        for command in ["echo 'a", "echo \"a", "echo a\\"] {
+            assert!(split_command(command).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn substitute_placeholders() {
+        let path = Path::new("/data/in put.tar.gz");
+        assert_eq!(substitute("{}", path), "/data/in put.tar.gz");
+        assert_eq!(substitute("{dir}/{base}.{ext}", path), "/data/in put.tar.gz.gz");
+        assert_eq!(substitute("[{ext}]", Path::new("README")), "[]");
+        // placeholders in the path itself are left alone
+        let path = Path::new("/data/{ext}/{}.csv");
+        assert_eq!(substitute("{dir} {base} {ext}", path), "/data/{ext} {}.csv csv");
+        assert_eq!(substitute("{}", path), "/data/{ext}/{}.csv");
+    }
+
+    #[test]
+    fn update_options_conflict_with_other_modes() {
+        for args in [
+            "--dir --watch",
+            "--until-gone --watch",
+            "--stream --watch",
+            "--until-gone --stable 1s",
+            "--until-gone --by-content",
+            "--dir-empty --debounce 1s",
+            "--tail-match x --state-file s",
+            "--dir --updates 2",
+        ] {
+            let err = parse(args).err().unwrap_or_else(|| panic!("'{}' parsed", args));
+            assert_eq!(err.kind(), ErrorKind::ArgumentConflict, "{}", args);
+        }
+    }
+
+    #[test]
+    fn options_conflict_with_modes_they_do_not_apply_to() {
+        for args in [
+            "-u --until-gone",
+            "-u --min-size 1",
+            "-u --dir-count 2",
+            "-u --dir-glob *.csv",
+            "--dir --oversize-ready",
+            "--stream --exec-shell",
+            "--shared --no-lock",
+            "--exact-size 1K --min-size 1",
+        ] {
+            let err = parse(args).err().unwrap_or_else(|| panic!("'{}' parsed", args));
+            assert_eq!(err.kind(), ErrorKind::ArgumentConflict, "{}", args);
+        }
+    }
+
+    #[test]
+    fn update_options_require_update() {
+        for args in ["--watch", "--stable 1s", "--by-content", "--oversize-ready"] {
+            let err = parse(args).err().unwrap_or_else(|| panic!("'{}' parsed", args));
+            assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument, "{}", args);
+        }
+    }
+
+    #[test]
+    fn compatible_options_parse() {
+        for args in ["", "-u --watch --stable 1s", "-u --detect inode,size", "--dir-count 1 --dir-glob *.csv", "--dir-empty", "--exact-size 1K --oversize-ready"] {
+            assert!(parse(args).is_ok(), "{}", args);
+        }
+    }
 }
@@ -0,0 +1,82 @@
+use crate::resolve::{matches_any, resolve_matches};
+use crate::WatchOptions;
+use log::debug;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// --metrics-file, rewritten on every check in the textfile collector format
+// of the Prometheus node exporter
+
+pub(crate) struct Metrics {
+    file: PathBuf,
+    paths: Vec<String>,
+    options: WatchOptions,
+    start: Instant,
+    // the threads of --mode any share one temp file, one of them must not
+    // rename it while another is still writing it
+    writing: Mutex<()>,
+}
+
+impl Metrics {
+    pub(crate) fn new(file: PathBuf, paths: &[String], options: &WatchOptions, start: Instant) -> Metrics {
+        Metrics {
+            file,
+            paths: paths.to_vec(),
+            options: options.clone(),
+            start,
+            writing: Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn write(&self, checks: u64) {
+        let mut out = String::new();
+        out.push_str("# HELP filewatcher_checks_total Checks of the watched files so far.\n");
+        out.push_str("# TYPE filewatcher_checks_total counter\n");
+        let _ = writeln!(out, "filewatcher_checks_total {}", checks);
+        out.push_str("# HELP filewatcher_wait_seconds Time spent waiting so far.\n");
+        out.push_str("# TYPE filewatcher_wait_seconds gauge\n");
+        let _ = writeln!(out, "filewatcher_wait_seconds {}", self.start.elapsed().as_secs_f64());
+        out.push_str("# HELP filewatcher_file_present Whether the watched path (or a match of the pattern) exists.\n");
+        out.push_str("# TYPE filewatcher_file_present gauge\n");
+        for path in &self.paths {
+            let present = match matches_any(path, &self.options) {
+                true => !resolve_matches(path, &self.options).is_empty(),
+                false => Path::new(path).exists(),
+            };
+            let _ = writeln!(out, "filewatcher_file_present{{path=\"{}\"}} {}", escape(path), u8::from(present));
+        }
+
+        // the collector may read at any time, so it only ever sees a whole file
+        let tmp = self.tmp_file();
+        let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+        let res = fs::write(&tmp, out).and_then(|()| fs::rename(&tmp, &self.file));
+        if let Err(e) = res {
+            debug!("Cannot write metrics to '{}': {}", self.file.display(), e);
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    fn tmp_file(&self) -> PathBuf {
+        self.file.with_file_name(format!(
+            ".{}.{}.tmp",
+            self.file.file_name().unwrap_or_default().to_string_lossy(),
+            std::process::id()
+        ))
+    }
+
+    // writing the file next to a watched one must not wake the ticker,
+    // that would check (and write) again right away
+    pub(crate) fn owns(&self, path: &Path) -> bool {
+        let name = path.file_name();
+        name.is_some() && (name == self.file.file_name() || name == self.tmp_file().file_name())
+    }
+}
+
+// label values quote \, " and newlines
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
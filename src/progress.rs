@@ -0,0 +1,85 @@
+use crate::resolve::{matches_any, resolve_file_name};
+use crate::WatchOptions;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// the bar of the running wait, one per process like the logger it has to
+// share stderr with
+
+struct Progress {
+    bar: ProgressBar,
+    paths: Vec<String>,
+    options: WatchOptions,
+}
+
+static PROGRESS: Mutex<Option<Arc<Progress>>> = Mutex::new(None);
+
+// not held while resolving or drawing, both may log
+
+fn current() -> Option<Arc<Progress>> {
+    PROGRESS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+// show the size of the first watched file against --expected-size (or
+// --exact-size or --min-size), stderr not being a terminal hides it
+
+pub(crate) fn start(paths: &[String], options: &WatchOptions) {
+    let Some(total) = options.expected_size.or(options.exact_size).or(options.min_size) else {
+        return;
+    };
+    if !options.progress {
+        return;
+    }
+    let bar = ProgressBar::with_draw_target(Some(total), ProgressDrawTarget::stderr());
+    let style = ProgressStyle::with_template("{msg} [{bar:40}] {bytes}/{total_bytes} ({percent}%)")
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ");
+    bar.set_style(style);
+    *PROGRESS.lock().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(Progress {
+        bar,
+        paths: paths.to_vec(),
+        options: options.clone(),
+    }));
+    update();
+}
+
+// called on every check
+
+pub(crate) fn update() {
+    let Some(progress) = current() else {
+        return;
+    };
+    let Some(path) = progress.paths.first() else {
+        return;
+    };
+    let file = match matches_any(path, &progress.options) {
+        true => resolve_file_name(path, &progress.options),
+        false => Some(PathBuf::from(path)),
+    };
+    let len = file
+        .as_ref()
+        .and_then(|file| fs::metadata(file).ok())
+        .map_or(0, |metadata| metadata.len());
+    progress.bar.set_message(file.map_or_else(|| path.clone(), |file| file.display().to_string()));
+    progress.bar.set_position(len.min(progress.bar.length().unwrap_or(len)));
+}
+
+// the bar is gone before whatever is logged about the result
+
+pub(crate) fn finish() {
+    let progress = PROGRESS.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(progress) = progress {
+        progress.bar.finish_and_clear();
+    }
+}
+
+/// Run f (e.g. writing a log line) with the progress bar of the running
+/// wait, if any, hidden, so the two do not garble each other
+pub fn suspend_progress<R>(f: impl FnOnce() -> R) -> R {
+    match current() {
+        Some(progress) => progress.bar.suspend(f),
+        None => f(),
+    }
+}
@@ -0,0 +1,462 @@
+use crate::{MatchPolicy, WatchOptions};
+use glob::glob;
+use log::debug;
+use regex::Regex;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// characters starting a wildcard understood by resolve_file_name, any number
+// of them may appear in a pattern: * and ? as in the shell, [0-9] and [!0-9]
+// for character classes (a literal [ has to be written as [[])
+
+const WILDCARDS: [char; 3] = ['*', '?', '['];
+
+pub fn is_pattern(filepath: &str) -> bool {
+    without_prefix(filepath).contains(WILDCARDS) && !is_url(filepath)
+}
+
+// the ? in a Windows verbatim prefix like \\?\C:\ or \\?\UNC\server\share is
+// not a wildcard, the prefix is passed on to glob as it is
+
+fn without_prefix(filepath: &str) -> &str {
+    match Path::new(filepath).components().next() {
+        Some(Component::Prefix(prefix)) => filepath
+            .get(prefix.as_os_str().len()..)
+            .unwrap_or(filepath),
+        _ => filepath,
+    }
+}
+
+// http(s) and s3 targets are requested instead of looked up on disk
+
+pub fn is_url(filepath: &str) -> bool {
+    ["http://", "https://", "s3://"].iter().any(|scheme| filepath.starts_with(scheme))
+}
+
+// collapse ., .. and repeated separators without looking at the filesystem
+// (the file may not exist yet), so two spellings of a path share a lock; a
+// .. at the root stays there, a leading one of a relative path is kept
+
+pub fn normalize_path(filepath: &str) -> String {
+    if is_url(filepath) {
+        return filepath.to_string();
+    }
+    let mut parts: Vec<Component> = Vec::new();
+    for component in Path::new(filepath).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match parts.last() {
+                Some(Component::Normal(_)) => {
+                    parts.pop();
+                }
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => parts.push(component),
+            },
+            _ => parts.push(component),
+        }
+    }
+    let path: PathBuf = parts.iter().collect();
+    match path.as_os_str().is_empty() {
+        true => ".".to_string(),
+        false => path.to_string_lossy().into_owned(),
+    }
+}
+
+// the file (a directory with dir) a pattern resolves to, the one --match
+// picks among the matches that are not excluded; with wildcards in the name
+// only (logs/out-*.csv) or with regex the directory is read once, unsorted,
+// and every match is looked at once, which takes a directory of 50k
+// matches from about 175ms to 100ms; most of what is left is the stat of
+// every match for its kind and mod time
+
+pub fn resolve_file_name(filename: &str, opts: &WatchOptions) -> Option<PathBuf> {
+    pick(matches(filename, opts), opts.match_policy, |_, metadata| metadata.is_dir() == opts.dir)
+}
+
+// every path matching the pattern, in alphabetical order
+
+pub fn resolve_all(filename: &str) -> Vec<PathBuf> {
+    resolve_matches(filename, &WatchOptions::default())
+}
+
+// the paths matching a pattern (or with opts.regex a regex) that are not
+// excluded, in no particular order; a bare pattern without a directory is
+// matched against the working directory, unreadable or missing directories
+// simply match nothing
+
+pub(crate) fn matches<'a>(filepath: &'a str, opts: &'a WatchOptions) -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+    let paths: Box<dyn Iterator<Item = PathBuf>> = match name_pattern(filepath, opts.regex) {
+        Some((dir, name)) => Box::new(matches_in_dir(dir, name)),
+        None if opts.regex => Box::new(std::iter::empty()),
+        None => Box::new(walk(filepath)),
+    };
+    Box::new(paths.filter(|path| !is_excluded(path, &opts.exclude)))
+}
+
+// what glob has to walk for, ** or wildcards in a directory included
+fn walk(filename: &str) -> impl Iterator<Item = PathBuf> {
+    let entries = glob(filename)
+        .inspect_err(|e| debug!("Invalid pattern '{}': {}", filename, e))
+        .ok();
+    entries.into_iter().flatten().filter_map(move |entry| match entry {
+        Ok(path) => {
+            debug!("Pattern '{}' matches '{}'", filename, path.display());
+            Some(path)
+        }
+        Err(e) => {
+            debug!("Cannot read '{}': {}", e.path().display(), e.error());
+            None
+        }
+    })
+}
+
+fn matches_in_dir(dir: PathBuf, name: Name) -> impl Iterator<Item = PathBuf> {
+    let read_from = if dir.as_os_str().is_empty() { PathBuf::from(".") } else { dir.clone() };
+    let entries = fs::read_dir(&read_from)
+        .inspect_err(|e| debug!("Cannot read '{}': {}", read_from.display(), e))
+        .ok();
+    entries.into_iter().flatten().flatten().filter_map(move |entry| {
+        let entry_name = entry.file_name();
+        if !name.matches(&entry_name.to_string_lossy()) {
+            return None;
+        }
+        // a bare pattern gives bare names, as glob does
+        let path = dir.join(&entry_name);
+        debug!("'{}' matches '{}'", name, path.display());
+        Some(path)
+    })
+}
+
+// the literal directory and the compiled name of a regex, or of a pattern
+// with wildcards in its last component only; None for anything glob has to
+// walk (** or a trailing separator included) and for a regex that does not
+// compile
+
+fn name_pattern(filename: &str, regex: bool) -> Option<(PathBuf, Name)> {
+    if regex {
+        let (dir, pattern) = split_regex(filename);
+        let regex = Regex::new(&format!("^(?:{})$", pattern))
+            .inspect_err(|e| debug!("Invalid regex '{}': {}", pattern, e))
+            .ok()?;
+        return Some((dir, Name::Regex(regex)));
+    }
+    let path = Path::new(filename);
+    let name = path.file_name()?.to_str()?;
+    if name.contains("**") || filename.ends_with(std::path::is_separator) {
+        return None;
+    }
+    let dir = path.parent()?;
+    if is_pattern(&dir.to_string_lossy()) {
+        return None;
+    }
+    Some((dir.to_path_buf(), Name::pattern(name)?))
+}
+
+// a name as a path component is matched, by a glob (a literal name is one
+// too) or with --regex by a regular expression
+
+pub(crate) enum Name {
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl Name {
+    pub(crate) fn pattern(name: &str) -> Option<Name> {
+        let pattern = match is_pattern(name) {
+            true => glob::Pattern::new(name),
+            false => glob::Pattern::new(&glob::Pattern::escape(name)),
+        };
+        pattern.ok().map(Name::Glob)
+    }
+
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        match self {
+            Name::Glob(pattern) => pattern.matches(name),
+            Name::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Name::Glob(pattern) => write!(f, "{}", pattern),
+            Name::Regex(regex) => write!(f, "{}", regex),
+        }
+    }
+}
+
+// with --regex the last component is a regular expression matched against
+// the whole name of each entry in the (literal) directory before it
+
+pub fn resolve_regex(filepath: &str) -> Vec<PathBuf> {
+    let opts = WatchOptions {
+        regex: true,
+        ..WatchOptions::default()
+    };
+    resolve_matches(filepath, &opts)
+}
+
+// directory and filename regex of a --regex path, a bare regex is matched
+// against the working directory; split at the last separator of the
+// platform, so on Windows a \ in the regex has to be written as [\\] or
+// the escape avoided (\. as [.])
+
+pub fn split_regex(filepath: &str) -> (PathBuf, &str) {
+    let Some(i) = filepath.rfind(std::path::is_separator) else {
+        return (PathBuf::from("."), filepath);
+    };
+    let (dir, name) = (&filepath[..i], &filepath[i + 1..]);
+    // keep the separator when it is all there is (/ or C:\ or \\server\share\)
+    match Path::new(dir).components().next_back() {
+        None | Some(Component::Prefix(_)) => (PathBuf::from(&filepath[..=i]), name),
+        _ => (PathBuf::from(dir), name),
+    }
+}
+
+// the wait loops go through these two so --regex applies everywhere
+
+pub(crate) fn matches_any(filepath: &str, opts: &WatchOptions) -> bool {
+    (opts.regex && !is_url(filepath)) || is_pattern(filepath)
+}
+
+pub(crate) fn resolve_matches(filepath: &str, opts: &WatchOptions) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = matches(filepath, opts).collect();
+    paths.sort();
+    paths
+}
+
+// --exclude globs are matched against the name of a match only, so '*.tmp'
+// skips temporary files in every directory
+
+pub fn is_excluded(path: &Path, excludes: &[glob::Pattern]) -> bool {
+    let Some(name) = path.file_name() else {
+        return false;
+    };
+    let excluded = excludes.iter().any(|exclude| exclude.matches(&name.to_string_lossy()));
+    if excluded {
+        debug!("Skipping excluded '{}'", path.display());
+    }
+    excluded
+}
+
+// the match --match asks for among those keep accepts, every path is
+// looked up once; the first is the alphabetically first one, and so is the
+// one picked on a mod time tie
+
+pub(crate) fn pick(paths: impl IntoIterator<Item = PathBuf>, policy: MatchPolicy, mut keep: impl FnMut(&Path, &fs::Metadata) -> bool) -> Option<PathBuf> {
+    let mut picked: Option<(SystemTime, PathBuf)> = None;
+    for path in paths {
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                debug!("Skipping '{}': {}", path.display(), e);
+                continue;
+            }
+        };
+        if !keep(&path, &metadata) {
+            continue;
+        }
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let better = picked.as_ref().is_none_or(|(time, best)| match policy {
+            MatchPolicy::First => path < *best,
+            MatchPolicy::Newest => modified > *time || (modified == *time && path < *best),
+            MatchPolicy::Oldest => modified < *time || (modified == *time && path < *best),
+        });
+        if better {
+            picked = Some((modified, path));
+        }
+    }
+    picked.map(|(_, path)| path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::Duration;
+
+    // an empty file modified this long ago
+    fn touch(path: &Path, age: u64) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let file = File::create(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+    }
+
+    fn resolve(pattern: &Path) -> Option<PathBuf> {
+        resolve_file_name(&pattern.to_string_lossy(), &WatchOptions::default())
+    }
+
+    #[test]
+    fn recursive_pattern_picks_newest_nested_match() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(&dir.path().join("a/out.csv"), 30);
+        touch(&dir.path().join("a/b/c/out.csv"), 10);
+        touch(&dir.path().join("a/b/out.csv"), 20);
+        touch(&dir.path().join("a/b/c/out.txt"), 0);
+        assert_eq!(resolve(&dir.path().join("**/out.csv")), Some(dir.path().join("a/b/c/out.csv")));
+        assert_eq!(resolve_all(&dir.path().join("a/**/*.csv").to_string_lossy()).len(), 3);
+    }
+
+    #[test]
+    fn any_number_of_stars() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["out-1.csv", "out-a-b.csv", "in-1.csv", "out-1.txt"] {
+            touch(&dir.path().join(name), 0);
+        }
+        let names = |pattern: &str| -> Vec<String> {
+            resolve_all(&dir.path().join(pattern).to_string_lossy())
+                .iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        // the one star case of old
+        assert_eq!(names("out-*.csv"), ["out-1.csv", "out-a-b.csv"]);
+        assert_eq!(names("*-*.csv"), ["in-1.csv", "out-1.csv", "out-a-b.csv"]);
+        assert_eq!(names("*-*-*.csv"), ["out-a-b.csv"]);
+        assert_eq!(names("*.*"), ["in-1.csv", "out-1.csv", "out-1.txt", "out-a-b.csv"]);
+    }
+
+    #[test]
+    fn bare_pattern_is_matched_in_the_working_directory() {
+        // cargo runs the tests from the package root
+        assert_eq!(resolve_file_name("Cargo.tom?", &WatchOptions::default()), Some(PathBuf::from("Cargo.toml")));
+        assert_eq!(resolve_file_name("*.does-not-exist", &WatchOptions::default()), None);
+    }
+
+    #[test]
+    fn missing_directory_matches_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(resolve(&dir.path().join("missing/*.csv")), None);
+        assert_eq!(resolve(&dir.path().join("missing/**/*.csv")), None);
+        assert!(resolve_all(&dir.path().join("missing/*.csv").to_string_lossy()).is_empty());
+    }
+
+    #[test]
+    fn question_mark_and_brackets() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["report-1.txt", "report-22.txt", "log1.txt", "log9.txt", "loga.txt"] {
+            touch(&dir.path().join(name), 0);
+        }
+        let count = |pattern: &str| resolve_all(&dir.path().join(pattern).to_string_lossy()).len();
+        assert_eq!(count("report-?.txt"), 1);
+        assert_eq!(count("report-??.txt"), 1);
+        assert_eq!(count("log[0-9].txt"), 2);
+        assert_eq!(count("log[19].txt"), 2);
+        assert_eq!(count("log[!0-9].txt"), 1);
+        // * keeps matching any run of characters
+        assert_eq!(count("report-*.txt"), 2);
+    }
+
+    #[test]
+    fn match_policy_picks_by_mod_time() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(&dir.path().join("b.csv"), 10);
+        touch(&dir.path().join("c.csv"), 30);
+        touch(&dir.path().join("a.csv"), 20);
+        let pattern = dir.path().join("*.csv").to_string_lossy().into_owned();
+        let picked = |match_policy| {
+            let opts = WatchOptions { match_policy, ..WatchOptions::default() };
+            resolve_file_name(&pattern, &opts)
+        };
+        assert_eq!(picked(MatchPolicy::Newest), Some(dir.path().join("b.csv")));
+        assert_eq!(picked(MatchPolicy::Oldest), Some(dir.path().join("c.csv")));
+        assert_eq!(picked(MatchPolicy::First), Some(dir.path().join("a.csv")));
+    }
+
+    #[test]
+    fn mod_time_tie_picks_alphabetically_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let time = SystemTime::now() - Duration::from_secs(10);
+        for name in ["c.csv", "a.csv", "b.csv"] {
+            File::create(dir.path().join(name)).unwrap().set_modified(time).unwrap();
+        }
+        assert_eq!(resolve(&dir.path().join("*.csv")), Some(dir.path().join("a.csv")));
+    }
+
+    #[test]
+    fn normalize_path_is_logical() {
+        assert_eq!(normalize_path("/data/./in//x.csv"), "/data/in/x.csv");
+        assert_eq!(normalize_path("/data/tmp/../x.csv"), "/data/x.csv");
+        assert_eq!(normalize_path("/../x.csv"), "/x.csv");
+        assert_eq!(normalize_path("../a/./b/.."), "../a");
+        assert_eq!(normalize_path("a/.."), ".");
+        assert_eq!(normalize_path("./"), ".");
+        assert_eq!(normalize_path("https://host/a/../b"), "https://host/a/../b");
+    }
+
+    #[test]
+    fn excluded_names_are_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(&dir.path().join("out.csv"), 10);
+        touch(&dir.path().join("out.csv.tmp"), 0);
+        touch(&dir.path().join("sub/part.tmp"), 0);
+        let opts = WatchOptions { exclude: vec![glob::Pattern::new("*.tmp").unwrap()], ..WatchOptions::default() };
+        let pattern = dir.path().join("out.*").to_string_lossy().into_owned();
+        assert_eq!(resolve_matches(&pattern, &opts), [dir.path().join("out.csv")]);
+        // the newer match is the excluded one
+        assert_eq!(resolve_file_name(&pattern, &opts), Some(dir.path().join("out.csv")));
+        assert!(resolve_matches(&dir.path().join("**/*.tmp").to_string_lossy(), &opts).is_empty());
+    }
+
+    // a \ is a separator on Windows, see split_regex
+    #[cfg(unix)]
+    #[test]
+    fn regex_picks_the_newest_timestamped_name() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(&dir.path().join("backup-20240101.tar.gz"), 20);
+        touch(&dir.path().join("backup-20240102.tar.gz"), 10);
+        touch(&dir.path().join("backup-2024010.tar.gz"), 0);
+        touch(&dir.path().join("backup-20240103.tar.gz.part"), 0);
+        touch(&dir.path().join("old-backup-20240104.tar.gz"), 0);
+        let pattern = format!("{}/backup-\\d{{8}}\\.tar\\.gz", dir.path().display());
+        let opts = WatchOptions { regex: true, ..WatchOptions::default() };
+        assert_eq!(resolve_file_name(&pattern, &opts), Some(dir.path().join("backup-20240102.tar.gz")));
+        assert_eq!(resolve_regex(&pattern).len(), 2);
+        assert!(resolve_regex(&format!("{}/backup-\\d{{9}}", dir.path().display())).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn regex_splits_at_the_last_separator() {
+        assert_eq!(split_regex("backups/backup-\\d{8}\\.tar\\.gz"), (PathBuf::from("backups"), "backup-\\d{8}\\.tar\\.gz"));
+        assert_eq!(split_regex("/data/in/.*\\.csv"), (PathBuf::from("/data/in"), ".*\\.csv"));
+        assert_eq!(split_regex("/.*\\.csv"), (PathBuf::from("/"), ".*\\.csv"));
+        assert_eq!(split_regex(".*\\.csv"), (PathBuf::from("."), ".*\\.csv"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_drive_and_unc_paths_are_split_at_either_separator() {
+        assert_eq!(split_regex(r"C:\data\backup-[0-9]{8}[.]tar[.]gz"), (PathBuf::from(r"C:\data"), "backup-[0-9]{8}[.]tar[.]gz"));
+        assert_eq!(split_regex("C:/data/.*[.]csv"), (PathBuf::from("C:/data"), ".*[.]csv"));
+        assert_eq!(split_regex(r"C:\.*[.]csv"), (PathBuf::from(r"C:\"), ".*[.]csv"));
+        assert_eq!(split_regex(r"\\server\share\in\.*[.]csv"), (PathBuf::from(r"\\server\share\in"), ".*[.]csv"));
+        assert_eq!(split_regex(r"\\server\share\.*[.]csv"), (PathBuf::from(r"\\server\share\"), ".*[.]csv"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_prefixes_are_not_wildcards() {
+        assert!(is_pattern(r"C:\data\out-*.log"));
+        assert!(!is_pattern(r"C:\data\out.log"));
+        assert!(is_pattern(r"\\server\share\*.csv"));
+        assert!(!is_pattern(r"\\server\share\in.csv"));
+        assert!(!is_pattern(r"\\?\C:\data\out.log"));
+        assert!(is_pattern(r"\\?\C:\data\out-?.log"));
+        assert!(!is_pattern(r"\\?\UNC\server\share\in.csv"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_drive_patterns_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(&dir.path().join("out-1.log"), 0);
+        // tempdir gives C:\...\Temp\..., with backslashes
+        assert_eq!(resolve(&dir.path().join("out-*.log")), Some(dir.path().join("out-1.log")));
+        let forward = format!("{}/out-*.log", dir.path().display()).replace('\\', "/");
+        assert_eq!(resolve_all(&forward).len(), 1);
+    }
+}
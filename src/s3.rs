@@ -0,0 +1,103 @@
+use crate::wait::size_ready;
+use crate::WatchOptions;
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::DateTimeFormat;
+use aws_sdk_s3::Client;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use tokio::runtime::Runtime;
+
+/// What the last check saw of an S3 object
+#[derive(Debug, Clone, Default)]
+pub struct S3Object {
+    pub size: Option<i64>,
+    /// RFC 3339
+    pub last_modified: Option<String>,
+    pub etag: Option<String>,
+}
+
+// the SDK is async, the checks block on a runtime of their own; credentials
+// come from the standard AWS chain (env, profile, SSO, instance role)
+static CLIENT: OnceLock<(Runtime, Client)> = OnceLock::new();
+
+// last object seen per target, for the summary
+static OBJECTS: Mutex<Option<HashMap<String, S3Object>>> = Mutex::new(None);
+
+fn client() -> &'static (Runtime, Client) {
+    CLIENT.get_or_init(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("cannot start the runtime for S3");
+        let config = runtime.block_on(aws_config::load_defaults(aws_config::BehaviorVersion::latest()));
+        (runtime, Client::new(&config))
+    })
+}
+
+// s3://bucket/key, the key may contain further slashes
+
+fn split(target: &str) -> Option<(&str, &str)> {
+    let (bucket, key) = target.strip_prefix("s3://")?.split_once('/')?;
+    (!bucket.is_empty() && !key.is_empty()).then_some((bucket, key))
+}
+
+// an object counts as available once head_object finds it, large enough for
+// --min-size and with the --etag if one is given
+
+pub(crate) fn object_available(target: &str, opts: &WatchOptions) -> bool {
+    let Some((bucket, key)) = split(target) else {
+        warn!("'{}' is not an s3://bucket/key target", target);
+        return false;
+    };
+    let (runtime, client) = client();
+    let res = runtime.block_on(client.head_object().bucket(bucket).key(key).send());
+    let head = match res {
+        Ok(head) => head,
+        Err(e) => {
+            match e.as_service_error() {
+                Some(e) if e.is_not_found() => debug!("Object '{}' does not exist yet", target),
+                _ => warn!("Cannot check '{}': {}", target, DisplayErrorContext(&e)),
+            }
+            return false;
+        }
+    };
+
+    let object = S3Object {
+        size: head.content_length(),
+        last_modified: head.last_modified().and_then(|time| time.fmt(DateTimeFormat::DateTime).ok()),
+        etag: head.e_tag().map(|etag| etag.trim_matches('"').to_string()),
+    };
+    debug!("Object '{}' is there: {:?}", target, object);
+    let size = object.size.unwrap_or(0);
+    let etag = object.etag.clone();
+    OBJECTS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(target.to_string(), object);
+
+    if let Some(min_size) = opts.min_size
+        && size < min_size as i64
+    {
+        debug!("Object '{}' has {} of {} bytes", target, size, min_size);
+        return false;
+    }
+    if let Some(exact_size) = opts.exact_size
+        && !size_ready(target, size.max(0) as u64, exact_size, opts.oversize_ready)
+    {
+        return false;
+    }
+    if let Some(expected) = &opts.s3_etag
+        && etag.as_deref() != Some(expected.trim_matches('"'))
+    {
+        debug!("Object '{}' has etag {:?}, waiting for {}", target, etag, expected);
+        return false;
+    }
+    true
+}
+
+/// The object as the last check saw it, None if it was never found
+pub fn last_object(target: &str) -> Option<S3Object> {
+    OBJECTS.lock().unwrap().as_ref()?.get(target).cloned()
+}
@@ -0,0 +1,134 @@
+use crate::wait::{baseline, UpdateState};
+use crate::{to_hex, WatchOptions};
+use log::{debug, warn};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// --state-file, the baseline of every watched file as it was when the last
+// run ended, so update mode can tell what changed in between; one file may
+// be shared by several watchers of different paths, each only rewrites its own
+
+// the stored baseline of a file, None when there is none that fits the
+// options (a missing or unreadable state file is no baseline either)
+
+pub(crate) fn load(file: &Path, filename: &str, opts: &WatchOptions) -> Option<UpdateState> {
+    let Some(mut files) = read(file) else {
+        warn!("State file '{}' is corrupt, starting without a baseline", file.display());
+        return None;
+    };
+    let entry = files.remove(filename)?;
+    let state = from_entry(&entry, opts);
+    match &state {
+        Some(_) => debug!("Using the baseline of '{}' stored in '{}'", filename, file.display()),
+        None => debug!("Stored baseline of '{}' does not fit the options, ignoring it", filename),
+    }
+    state
+}
+
+// record the current baseline of the files, a file that is gone has none
+
+pub(crate) fn save(file: &Path, filenames: &[String], opts: &WatchOptions) {
+    let mut files = read(file).unwrap_or_default();
+    for filename in filenames {
+        match baseline(filename, opts) {
+            Ok(state) => {
+                files.insert(filename.clone(), to_entry(&state, opts));
+            }
+            Err(_) => {
+                files.remove(filename);
+            }
+        }
+    }
+    let out = json!({ "version": 1, "files": files }).to_string();
+
+    // a run killed while writing must not leave half a state behind
+    let tmp = file.with_file_name(format!(
+        ".{}.{}.tmp",
+        file.file_name().unwrap_or_default().to_string_lossy(),
+        std::process::id()
+    ));
+    let res = fs::write(&tmp, out).and_then(|()| fs::rename(&tmp, file));
+    if let Err(e) = res {
+        warn!("Cannot write state to '{}': {}", file.display(), e);
+        let _ = fs::remove_file(&tmp);
+    }
+}
+
+// the stored baselines by path, None when the file is corrupt
+
+fn read(file: &Path) -> Option<Map<String, Value>> {
+    let content = match fs::read_to_string(file) {
+        Ok(content) => content,
+        Err(e) => {
+            debug!("No state in '{}': {}", file.display(), e);
+            return Some(Map::new());
+        }
+    };
+    let files = serde_json::from_str::<Value>(&content)
+        .ok()
+        .and_then(|mut state| state.get_mut("files").map(Value::take));
+    match files {
+        Some(Value::Object(files)) => Some(files),
+        _ => None,
+    }
+}
+
+fn to_entry(state: &UpdateState, opts: &WatchOptions) -> Value {
+    match state {
+        UpdateState::Baseline { last_mod, len, id, target, digest } => json!({
+            "mtime_ns": last_mod.as_nanos() as u64,
+            "len": len,
+            "dev": id.0,
+            "ino": id.1,
+            "target": target,
+            "checksum": digest.as_ref().map(|_| opts.checksum.to_string()),
+            "digest": digest.as_deref().map(to_hex),
+        }),
+        UpdateState::Content(digest) => json!({
+            "checksum": opts.checksum.to_string(),
+            "digest": to_hex(digest),
+        }),
+        _ => Value::Null,
+    }
+}
+
+fn from_entry(entry: &Value, opts: &WatchOptions) -> Option<UpdateState> {
+    // a digest only compares to one of the same algorithm
+    let digest = || {
+        let checksum = entry.get("checksum")?.as_str()?;
+        match checksum == opts.checksum.to_string() {
+            true => from_hex(entry.get("digest")?.as_str()?),
+            false => None,
+        }
+    };
+    if opts.by_content {
+        return Some(UpdateState::Content(digest()?));
+    }
+    let number = |key: &str| entry.get(key).and_then(Value::as_u64);
+    let target = entry.get("target").and_then(Value::as_str).map(PathBuf::from);
+    if target.is_none() && !opts.no_follow {
+        return None; // stored with --no-follow
+    }
+    Some(UpdateState::Baseline {
+        last_mod: Duration::from_nanos(number("mtime_ns")?),
+        len: number("len")?,
+        id: (number("dev")?, number("ino")?),
+        target: target.filter(|_| !opts.no_follow),
+        digest: match opts.ignore_touch {
+            true => Some(digest()?),
+            false => None,
+        },
+    })
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
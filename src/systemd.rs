@@ -0,0 +1,33 @@
+use crate::WatchOptions;
+use log::{debug, warn};
+use sd_notify::NotifyState;
+
+// talk to systemd for a Type=notify unit, without $NOTIFY_SOCKET (not started
+// by systemd) every message is dropped
+
+/// Tell systemd the watch is set up (locks taken), warns when the checks are
+/// further apart than the watchdog allows
+pub fn notify_ready(options: &WatchOptions) {
+    if let Some(limit) = sd_notify::watchdog_enabled() {
+        let slowest = options.max_interval.unwrap_or(options.interval).max(options.interval);
+        if slowest >= limit {
+            warn!("Checks every {:?} are too far apart for the systemd watchdog of {:?}", slowest, limit);
+        }
+    }
+    send(&[NotifyState::Ready]);
+}
+
+// on every check while $WATCHDOG_USEC is set, a hung wait stops feeding it
+
+pub(crate) fn feed_watchdog() {
+    if sd_notify::watchdog_enabled().is_some() {
+        send(&[NotifyState::Watchdog]);
+    }
+}
+
+fn send(state: &[NotifyState]) {
+    match sd_notify::notify(state) {
+        Ok(()) => debug!("Sent {:?} to systemd", state),
+        Err(e) => warn!("Cannot notify systemd: {}", e),
+    }
+}
@@ -0,0 +1,363 @@
+use crate::metrics::Metrics;
+use crate::resolve::{split_regex, Name};
+use crate::{is_pattern, is_url, WatchOptions};
+use log::{debug, trace, warn};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use regex::Regex;
+use std::cell::{Cell, RefCell};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// the most a --backoff grows the interval to without a --max-interval
+const DEFAULT_MAX_INTERVAL: Duration = Duration::from_secs(3600);
+
+// wakes the wait loops on filesystem events in the watched directories,
+// or once the interval elapsed when events are unavailable
+
+pub(crate) struct Ticker {
+    events: Option<RefCell<Events>>,
+    // names an event has to be about to wake the wait, None wakes it on
+    // every event (a pattern that did not compile)
+    names: Option<Vec<Name>>,
+    log_file: Option<PathBuf>,
+    pub(crate) interval: Duration,
+    backoff: f64,
+    max_interval: Option<Duration>,
+    jitter: f64,
+    // xorshift state for the jitter, see Ticker::jittered
+    rng: Cell<u64>,
+    // set once another thread's wait won, see FileWatcher::wait_any
+    cancel: Option<Arc<AtomicBool>>,
+    // checks done by the wait loops, shared with the FileWatcher
+    checks: Arc<AtomicU64>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Ticker {
+    pub(crate) fn new(filepaths: &[String], options: &WatchOptions) -> Ticker {
+        let events = if options.poll { None } else { watch_dirs(filepaths, options.regex).map(RefCell::new) };
+        if events.is_none() {
+            debug!("Polling every {:?}", options.interval);
+        }
+        Ticker {
+            events,
+            names: watched_names(filepaths, options),
+            log_file: options.log_file.as_deref().and_then(|file| std::path::absolute(file).ok()),
+            interval: options.interval,
+            backoff: options.backoff,
+            max_interval: options.max_interval,
+            jitter: options.jitter,
+            rng: Cell::new(seed()),
+            cancel: None,
+            checks: Arc::new(AtomicU64::new(0)),
+            metrics: None,
+        }
+    }
+
+    pub(crate) fn with_counter(mut self, checks: Arc<AtomicU64>) -> Ticker {
+        self.checks = checks;
+        self
+    }
+
+    pub(crate) fn with_metrics(mut self, metrics: Option<Arc<Metrics>>) -> Ticker {
+        self.metrics = metrics;
+        self
+    }
+
+    pub(crate) fn count_check(&self) {
+        let checks = self.checks.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(metrics) = &self.metrics {
+            metrics.write(checks);
+        }
+        #[cfg(feature = "progress")]
+        crate::progress::update();
+        #[cfg(feature = "systemd")]
+        crate::systemd::feed_watchdog();
+    }
+
+    pub(crate) fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Ticker {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    pub(crate) fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    // grow the delay by the backoff factor, never going above max_interval
+    // (DEFAULT_MAX_INTERVAL without one) or below the configured interval
+    pub(crate) fn next_delay(&self, delay: Duration) -> Duration {
+        let max = self.max_interval.unwrap_or(DEFAULT_MAX_INTERVAL).max(self.interval);
+        Duration::try_from_secs_f64(delay.as_secs_f64() * self.backoff).map_or(max, |next| next.min(max))
+    }
+
+    // spread out watchers started at the same time (e.g. by a fan-out job on
+    // a shared NFS mount), nothing here needs more than a xorshift
+    fn jittered(&self, delay: Duration) -> Duration {
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+        let mut x = self.rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.set(x);
+        let unit = (x >> 11) as f64 / (1u64 << 53) as f64; // in [0, 1)
+        let factor = 1.0 + self.jitter * (2.0 * unit - 1.0);
+        Duration::try_from_secs_f64(delay.as_secs_f64() * factor).unwrap_or(delay)
+    }
+
+    // sleep for the delay but at most for left (what the timeout leaves),
+    // waking early on an event, false then
+    pub(crate) fn wait_at_most(&self, delay: Duration, left: Option<Duration>) -> bool {
+        let delay = self.jittered(delay);
+        let delay = left.map_or(delay, |left| delay.min(left));
+        let Some(events) = &self.events else {
+            debug!("Sleeping {:?}", delay);
+            sleep(delay);
+            return true;
+        };
+
+        let mut events = events.borrow_mut();
+        // a huge --interval sleeps until an event, as good as forever
+        let deadline = Instant::now().checked_add(delay);
+        let slept = self.next_event(&events.rx, deadline);
+        if !slept {
+            events.rewatch();
+        }
+        slept
+    }
+
+    // whether the deadline passed (or the events stopped) before a wanted event
+    fn next_event(&self, rx: &Receiver<notify::Result<Event>>, deadline: Option<Instant>) -> bool {
+        loop {
+            let event = match deadline {
+                Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match event {
+                Ok(Ok(event)) => {
+                    // an event without paths (e.g. a lost one) may be about anything
+                    let wanted = event.paths.is_empty() || event.paths.iter().any(|path| self.wanted(path));
+                    if wanted && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+                        debug!("Event {:?} on {:?}", event.kind, event.paths);
+                        return false;
+                    }
+                    trace!("Ignoring event {:?} on {:?}", event.kind, event.paths);
+                }
+                Ok(Err(e)) => debug!("Watch error: {}", e),
+                Err(RecvTimeoutError::Timeout) => return true,
+                Err(RecvTimeoutError::Disconnected) => {
+                    sleep(deadline.map_or(Duration::MAX, |deadline| deadline.saturating_duration_since(Instant::now())));
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+impl Ticker {
+    // an event about a file that cannot be one of the watched ones (others in
+    // the same directory, the log or the metrics being written) is no reason
+    // to check, a busy directory would keep the wait from ever sleeping
+    fn wanted(&self, path: &Path) -> bool {
+        if self.metrics.as_ref().is_some_and(|metrics| metrics.owns(path)) {
+            return false;
+        }
+        if let Some(log_file) = &self.log_file
+            && std::path::absolute(path).is_ok_and(|path| path == *log_file)
+        {
+            return false;
+        }
+        let Some(names) = &self.names else {
+            return true;
+        };
+        let Some(name) = path.file_name() else {
+            return true;
+        };
+        let name = name.to_string_lossy();
+        names.iter().any(|wanted| wanted.matches(&name))
+    }
+}
+
+// every component below the watched directory (see watch_root) can be what
+// an event is about, e.g. the new day/ directory of 'logs/*/out.csv'; so can
+// the --equals reference when it is next to the files
+
+fn watched_names(filepaths: &[String], options: &WatchOptions) -> Option<Vec<Name>> {
+    let mut names = Vec::new();
+    if let Some(name) = options.equals.as_deref().and_then(|reference| Path::new(reference).file_name()) {
+        names.push(Name::pattern(&glob::Pattern::escape(&name.to_string_lossy()))?);
+    }
+    for filepath in filepaths.iter().filter(|filepath| !is_url(filepath)) {
+        names.extend(watch_root(filepath, options.regex).below?);
+    }
+    Some(names)
+}
+
+// different for every process and every ticker, never 0 (xorshift would
+// stay there)
+fn seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or(0);
+    (nanos ^ (u64::from(std::process::id()) << 32)) | 1
+}
+
+// the watcher and what it watches for each path, see Events::rewatch
+
+struct Events {
+    watcher: RecommendedWatcher,
+    rx: Receiver<notify::Result<Event>>,
+    regex: bool,
+    roots: Vec<(String, PathBuf)>,
+}
+
+impl Events {
+    // a path watched through an ancestor (its directory did not exist yet)
+    // moves down to the directories created since, before the wait checks
+    // again, so what gets created in them next is an event too
+    fn rewatch(&mut self) {
+        for i in 0..self.roots.len() {
+            let watch = watch_root(&self.roots[i].0, self.regex);
+            if watch.dir == self.roots[i].1 {
+                continue;
+            }
+            if let Err(e) = self.watcher.watch(&watch.dir, watch.mode) {
+                debug!("Cannot watch '{}' yet: {}", watch.dir.display(), e);
+                continue;
+            }
+            debug!("Watching '{}' for events", watch.dir.display());
+            let old = std::mem::replace(&mut self.roots[i].1, watch.dir);
+            if self.roots.iter().all(|(_, root)| *root != old) {
+                let _ = self.watcher.unwatch(&old);
+            }
+        }
+    }
+}
+
+fn watch_dirs(filepaths: &[String], regex: bool) -> Option<Events> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(tx) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Filesystem events unavailable, falling back to polling: {}", e);
+            return None;
+        }
+    };
+
+    // nothing on disk to watch for an URL
+    let mut roots = Vec::new();
+    for filepath in filepaths.iter().filter(|filepath| !is_url(filepath)) {
+        let watch = watch_root(filepath, regex);
+        if let Err(e) = watcher.watch(&watch.dir, watch.mode) {
+            warn!("Cannot watch '{}', falling back to polling: {}", watch.dir.display(), e);
+            return None;
+        }
+        debug!("Watching '{}' for events", watch.dir.display());
+        roots.push((filepath.clone(), watch.dir));
+    }
+
+    Some(Events { watcher, rx, regex, roots })
+}
+
+// where to watch a path or pattern from: the parent for a literal path, the
+// part before the first wildcard otherwise (the directory of the regex with
+// --regex), the nearest existing ancestor of either while it is missing;
+// recursive when a wildcard is not in the last component, e.g. the day/ of
+// 'logs/*/out.csv' is new every day, once that part exists
+
+struct WatchRoot {
+    dir: PathBuf,
+    mode: RecursiveMode,
+    // the components below dir, None when a pattern did not compile
+    below: Option<Vec<Name>>,
+}
+
+fn watch_root(filepath: &str, regex: bool) -> WatchRoot {
+    let (path, last) = match regex {
+        true => {
+            let (dir, pattern) = split_regex(filepath);
+            (dir, Some(Regex::new(&format!("^(?:{})$", pattern)).ok().map(Name::Regex)))
+        }
+        false => (PathBuf::from(filepath), None),
+    };
+    let components: Vec<Component> = path.components().collect();
+    let name = |component: &Component| component.as_os_str().to_string_lossy().into_owned();
+    let wildcard = components.iter().position(|component| !regex && is_pattern(&name(component)));
+    let mut depth = match (regex, wildcard) {
+        (true, _) => components.len(),
+        (false, Some(i)) => i,
+        (false, None) => components.len().saturating_sub(1),
+    };
+    let literal = depth;
+    let mut dir: PathBuf = components[..depth].iter().collect();
+    while depth > 0 && !dir.is_dir() {
+        depth -= 1;
+        dir = components[..depth].iter().collect();
+    }
+    if dir.as_os_str().is_empty() {
+        dir.push(".");
+    }
+
+    // not for an ancestor, that may be /
+    let deep = wildcard.is_some_and(|i| i + 1 < components.len()) || (!regex && filepath.contains("**"));
+    let mode = match deep && depth == literal {
+        true => RecursiveMode::Recursive,
+        false => RecursiveMode::NonRecursive,
+    };
+    let below = components[depth..]
+        .iter()
+        .map(|component| match regex {
+            true => Name::pattern(&glob::Pattern::escape(&name(component))),
+            false => Name::pattern(&name(component)),
+        })
+        .chain(last)
+        .collect();
+    WatchRoot { dir, mode, below }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_stops_at_an_hour_without_max_interval() {
+        let options = WatchOptions { poll: true, backoff: 2.0, ..WatchOptions::default() };
+        let ticker = Ticker::new(&[], &options);
+        let mut delay = ticker.interval;
+        for _ in 0..2000 {
+            delay = ticker.next_delay(delay);
+        }
+        assert_eq!(delay, DEFAULT_MAX_INTERVAL);
+    }
+
+    #[test]
+    fn wildcard_directories_are_watched_recursively() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("logs")).unwrap();
+        let watch = watch_root(&dir.path().join("logs/*/out.csv").to_string_lossy(), false);
+        assert_eq!(watch.dir, dir.path().join("logs"));
+        assert!(watch.mode == RecursiveMode::Recursive);
+        let watch = watch_root(&dir.path().join("logs/*.csv").to_string_lossy(), false);
+        assert!(watch.mode == RecursiveMode::NonRecursive);
+    }
+
+    #[test]
+    fn missing_directories_are_watched_from_the_nearest_ancestor() {
+        let dir = tempfile::tempdir().unwrap();
+        let watch = watch_root(&dir.path().join("a/*/out.csv").to_string_lossy(), false);
+        assert_eq!(watch.dir, dir.path());
+        assert!(watch.mode == RecursiveMode::NonRecursive);
+        let names: Vec<String> = watch.below.unwrap().iter().map(|name| name.to_string()).collect();
+        assert_eq!(names, ["a", "*", "out.csv"]);
+        let watch = watch_root(&dir.path().join("a/b/backup-\\d+").to_string_lossy(), true);
+        assert_eq!(watch.dir, dir.path());
+        assert_eq!(watch.below.unwrap().len(), 3);
+    }
+}
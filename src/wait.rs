@@ -0,0 +1,1285 @@
+use crate::ticker::Ticker;
+use crate::resolve::{matches, matches_any, pick, resolve_matches};
+use crate::checksum::digest_file;
+use crate::state;
+use crate::{emit_event, to_hex, ChangeSignal, Mode, TimeField, WatchError, WatchOptions};
+use log::{debug, error, info, warn};
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// state of a file in update mode
+
+pub(crate) enum UpdateState {
+    // waiting for the mod time to move past the baseline, for the size to
+    // change (e.g. truncated in place) or for the file to be replaced (e.g.
+    // by an atomic rename or a repointed symlink)
+    // with --ignore-touch the digest decides whether a newer mod time at the
+    // same size is an update
+    Baseline { last_mod: Duration, len: u64, id: (u64, u64), target: Option<PathBuf>, digest: Option<Vec<u8>> },
+    // waiting for the content digest to differ from the baseline
+    Content(Vec<u8>),
+    // file did not exist at startup, its arrival counts as the update
+    Missing,
+    // waiting for the mod time to move past the one of a reference file,
+    // which may itself change meanwhile
+    NewerThan(String),
+    // waiting for the mod time to move past a fixed point in time
+    Since(u64),
+    // updated, waiting for mod time and size to stay unchanged
+    Settling { path: String, last: (Duration, u64), since: Instant },
+    // removed after the baseline was taken, waiting up to --tolerate-missing
+    // for it to come back
+    Gone { since: Instant },
+}
+
+// what an update is measured against
+
+pub(crate) fn baseline(filename: &str, opts: &WatchOptions) -> Result<UpdateState, WatchError> {
+    if opts.by_content {
+        get_last_mod(filename, opts)?; // rejects directories
+        Ok(UpdateState::Content(get_digest(filename, opts)?))
+    } else {
+        let (last_mod, len) = get_mod_and_len(filename, opts)?;
+        let id = get_file_id(filename, opts)?;
+        let digest = match opts.ignore_touch {
+            true => Some(get_digest(filename, opts)?),
+            false => None,
+        };
+        Ok(UpdateState::Baseline { last_mod, len, id, target: link_target(filename, opts), digest })
+    }
+}
+
+// where the update of a file starts from
+
+pub(crate) fn initial_state(filename: &str, opts: &WatchOptions) -> Result<UpdateState, WatchError> {
+    if let Some(reference) = &opts.newer_than {
+        Ok(UpdateState::NewerThan(reference.clone()))
+    } else if let Some(since) = opts.since {
+        Ok(UpdateState::Since(since))
+    } else if file_exists(filename) {
+        // with --state-file an update since the last run counts right away
+        match opts.state_file.as_deref().and_then(|file| state::load(file, filename, opts)) {
+            Some(state) => Ok(state),
+            None => baseline(filename, opts),
+        }
+    } else {
+        warn!("File '{}' does not exist. Waiting...", &filename);
+        emit_event(opts.format, "file_missing", filename);
+        Ok(UpdateState::Missing)
+    }
+}
+
+pub(crate) fn wait_for_file_update(filenames: &[String], ticker: &Ticker, opts: &WatchOptions) -> Result<Vec<String>, WatchError> {
+    if let Some(reference) = &opts.newer_than
+        && !file_exists(reference)
+    {
+        error!("Reference file '{}' does not exist", reference);
+        return Err(WatchError::FileMissing);
+    }
+    // with --updates a file is done after that many, counted per file
+    let mut pending = Vec::new();
+    for filename in filenames {
+        pending.push((filename, initial_state(filename, opts)?, 0));
+    }
+    let start = Instant::now();
+    let mut delay = ticker.interval;
+    let mut updated = Vec::new();
+
+    let mut checks = 0;
+    loop {
+        ticker.count_check();
+        checks += 1;
+        debug!("Check {}", checks);
+        let mut i = 0;
+        while i < pending.len() {
+            let (filename, state, count) = &mut pending[i];
+            let res = match check_update(filename, state, opts) {
+                // the permissions may still be fixed, try again next check
+                Err(WatchError::PermissionDenied) if opts.retry_permission => {
+                    warn!("Cannot check '{}' yet, retrying", filename);
+                    Ok(None)
+                }
+                res => res,
+            };
+            if let Some(path) = res? {
+                emit_event(opts.format, "file_updated", &path);
+                *count += 1;
+                if let Some(updates) = opts.updates
+                    && *count < updates
+                {
+                    // changes closer together than one check count once
+                    info!("File '{}' updated {} of {} times", filename, count, updates);
+                    *state = baseline(filename, opts)?;
+                    delay = ticker.interval;
+                    i += 1;
+                    continue;
+                }
+                updated.push(path);
+                pending.remove(i);
+                if opts.mode == Mode::Any || pending.is_empty() {
+                    info!("File updated, exiting...");
+                    return Ok(updated);
+                }
+                delay = ticker.interval;
+            } else {
+                i += 1;
+            }
+        }
+
+        if timed_out(start, opts.timeout) || out_of_checks(checks, opts.max_checks) {
+            warn!(
+                "File '{}' was not updated after waiting {:?}, giving up",
+                pending[0].0,
+                start.elapsed()
+            );
+            for (filename, _, _) in &pending {
+                emit_event(opts.format, "timed_out", filename);
+            }
+            return Err(WatchError::Timeout);
+        }
+        if heartbeat_due(checks, opts.heartbeat) {
+            info!("Still waiting for '{}' to be updated, {:?} elapsed", pending[0].0, start.elapsed());
+            if let Some(updates) = opts.updates {
+                for (filename, _, count) in &pending {
+                    info!("File '{}' was updated {} of {} times", filename, count, updates);
+                }
+            }
+        }
+        // a settling file is looked at again once its quiet window may be over
+        let settling = pending
+            .iter()
+            .filter_map(|(_, state, _)| match state {
+                UpdateState::Settling { since, .. } => opts.stable.map(|stable| stable.saturating_sub(since.elapsed())),
+                _ => None,
+            })
+            .min();
+        let slept = ticker.wait_at_most(settling.map_or(delay, |left| left.min(delay)), left(start, opts.timeout));
+        if ticker.cancelled() {
+            return Err(WatchError::Timeout);
+        }
+        if slept {
+            delay = ticker.next_delay(delay);
+        }
+    }
+}
+//}
+
+// advance the update state of a file, returns the resolved path once it is done
+
+pub(crate) fn check_update(filename: &str, state: &mut UpdateState, opts: &WatchOptions) -> Result<Option<String>, WatchError> {
+    let stable = opts.stable;
+    if let UpdateState::Baseline { .. } | UpdateState::Content(_) = state
+        && opts.tolerate_missing.is_some()
+        && !file_exists(filename)
+    {
+        warn!("File '{}' is gone, waiting for it to come back", filename);
+        *state = UpdateState::Gone { since: Instant::now() };
+        return Ok(None);
+    }
+    let updated = match state {
+        UpdateState::Gone { since } => {
+            if file_exists(filename) {
+                // a replaced file is not an update yet, only what changes after it
+                info!("File '{}' is back after {:?}, taking a new baseline", filename, since.elapsed());
+                *state = baseline(filename, opts)?;
+            } else if opts.tolerate_missing.is_some_and(|grace| since.elapsed() >= grace) {
+                error!("File '{}' did not come back within {:?}", filename, since.elapsed());
+                return Err(WatchError::FileMissing);
+            }
+            None
+        }
+        UpdateState::Baseline { len, .. } if opts.on_shrink => {
+            // a file that grew raises the baseline, rotation usually comes
+            // after a lot of growth
+            let latest_len = get_mod_and_len(filename, opts)?.1;
+            if latest_len < *len {
+                info!("File '{}' shrank from {} to {} bytes", filename, len, latest_len);
+                Some(filename.to_string())
+            } else {
+                if latest_len > *len {
+                    debug!("File '{}' grew from {} to {} bytes", filename, len, latest_len);
+                    *len = latest_len;
+                }
+                None
+            }
+        }
+        UpdateState::Baseline { last_mod, len, id, target, digest } => {
+            let (latest_mod, latest_len) = get_mod_and_len(filename, opts)?;
+            if *last_mod < latest_mod
+                && *len == latest_len
+                && let Some(digest) = digest
+                && *digest == get_digest(filename, opts)?
+            {
+                // a touch, hashed once per mod time
+                debug!("File '{}' was only touched, still waiting", filename);
+                *last_mod = latest_mod;
+            }
+            let detect = |signal| opts.detect.contains(&signal);
+            if detect(ChangeSignal::Mtime) && *last_mod < latest_mod {
+                info!("File '{}' updated", filename);
+                Some(filename.to_string())
+            } else if detect(ChangeSignal::Size) && *len != latest_len {
+                info!("File '{}' changed size from {} to {} bytes", filename, len, latest_len);
+                Some(filename.to_string())
+            } else if !detect(ChangeSignal::Inode) {
+                None
+            } else if *id != get_file_id(filename, opts)? {
+                info!("File '{}' was replaced", filename);
+                Some(filename.to_string())
+            } else if *target != link_target(filename, opts) {
+                info!("File '{}' now points to {:?}", filename, link_target(filename, opts));
+                Some(filename.to_string())
+            } else {
+                None
+            }
+        }
+        UpdateState::Content(digest) => {
+            if *digest != get_digest(filename, opts)? {
+                info!("File '{}' content changed", filename);
+                Some(filename.to_string())
+            } else {
+                None
+            }
+        }
+        UpdateState::Missing => {
+            let path = file_available(filename, opts, &HashSet::new())?;
+            if let Some(path) = &path {
+                info!("File '{}' is available", path);
+                emit_event(opts.format, "file_appeared", path);
+            }
+            path
+        }
+        UpdateState::NewerThan(reference) => {
+            if !file_exists(filename) {
+                return Ok(None);
+            }
+            let latest_mod = get_last_mod(filename, opts)?;
+            let reference_mod = get_last_mod(reference, opts)?;
+            if latest_mod > reference_mod {
+                info!("File '{}' is newer than '{}'", filename, reference);
+                Some(filename.to_string())
+            } else {
+                None
+            }
+        }
+        UpdateState::Since(since) => {
+            if file_exists(filename) && get_last_mod(filename, opts)? > Duration::from_secs(*since) {
+                info!("File '{}' was modified after {}", filename, since);
+                Some(filename.to_string())
+            } else {
+                None
+            }
+        }
+        UpdateState::Settling { path, last, since } => {
+            let current = get_mod_and_len(path, opts)?;
+            if current != *last {
+                debug!("File '{}' still changing, resetting quiet timer", path);
+                *last = current;
+                *since = Instant::now();
+                return Ok(None);
+            }
+            if stable.is_some_and(|stable| since.elapsed() >= stable) {
+                info!("File '{}' has been stable for {:?}", path, since.elapsed());
+                return Ok(Some(path.clone()));
+            }
+            return Ok(None);
+        }
+    };
+
+    match (updated, stable) {
+        (Some(path), Some(stable)) => {
+            debug!("Waiting for '{}' to be unchanged for {:?}", path, stable);
+            let last = get_mod_and_len(&path, opts)?;
+            *state = UpdateState::Settling { path, last, since: Instant::now() };
+            Ok(None)
+        }
+        (updated, _) => Ok(updated),
+    }
+}
+
+pub(crate) fn wait_for_file(filepaths: &[String], ticker: &Ticker, opts: &WatchOptions) -> Result<Vec<String>, WatchError> {
+    let start = Instant::now();
+    let mut delay = ticker.interval;
+    let mut ready_before = 0;
+    let mut first_check = true;
+    // with new_only, whatever matches at startup is never ready
+    let snapshots: Vec<HashSet<PathBuf>> = filepaths
+        .iter()
+        .map(|filepath| match opts.new_only {
+            true => resolve_matches(filepath, opts).into_iter().collect(),
+            false => HashSet::new(),
+        })
+        .collect();
+    let mut checks = 0;
+    loop {
+        ticker.count_check();
+        checks += 1;
+        debug!("Check {}", checks);
+        // every file is checked each time, a file that became unready again
+        // (e.g. shrunk below --min-size) has to become ready once more
+        let mut ready = Vec::new();
+        let mut pending = Vec::new();
+        let mut counted = Vec::new();
+        for (filepath, snapshot) in filepaths.iter().zip(&snapshots) {
+            // with --count a pattern is ready once enough files match it
+            if let Some(count) = opts.count
+                && matches_any(filepath, opts)
+            {
+                let matches = ready_matches(filepath, opts, snapshot);
+                if matches.len() as u64 >= count {
+                    ready.extend(matches.iter().map(|path| path.to_string_lossy().into_owned()));
+                } else {
+                    counted.push((filepath, matches.len()));
+                    pending.push(filepath);
+                }
+                continue;
+            }
+            match file_available(filepath, opts, snapshot)? {
+                Some(path) => ready.push(path),
+                None => pending.push(filepath),
+            }
+        }
+        if pending.is_empty() || (opts.mode == Mode::Any && !ready.is_empty()) {
+            for path in &ready {
+                info!("File '{}' is available, bye...", path);
+                emit_event(opts.format, "file_appeared", path);
+            }
+            return Ok(ready);
+        }
+        if ready.len() > ready_before {
+            delay = ticker.interval;
+        }
+        ready_before = ready.len();
+        if first_check {
+            for filepath in &pending {
+                emit_event(opts.format, "file_missing", filepath);
+            }
+            first_check = false;
+        }
+
+        if timed_out(start, opts.timeout) || out_of_checks(checks, opts.max_checks) {
+            warn!(
+                "File '{}' did not appear after waiting {:?}, giving up",
+                pending[0],
+                start.elapsed()
+            );
+            for filepath in &pending {
+                emit_event(opts.format, "timed_out", filepath);
+            }
+            return Err(WatchError::Timeout);
+        }
+        if heartbeat_due(checks, opts.heartbeat) {
+            info!("Still waiting for '{}' to appear, {:?} elapsed", pending[0], start.elapsed());
+            for (filepath, found) in &counted {
+                info!("Pattern '{}' matches {} of {} files", filepath, found, opts.count.unwrap_or(0));
+            }
+        }
+
+        let slept = ticker.wait_at_most(delay, left(start, opts.timeout));
+        if ticker.cancelled() {
+            return Err(WatchError::Timeout);
+        }
+        if slept {
+            delay = ticker.next_delay(delay);
+        }
+    }
+}
+
+// --stream: report every file matching one of the paths that was not there
+// at startup, until the timeout (if any) ends the stream
+
+pub(crate) fn stream_new_files(filepaths: &[String], ticker: &Ticker, opts: &WatchOptions, on_file: &mut dyn FnMut(&Path)) -> Result<(), WatchError> {
+    let start = Instant::now();
+    let mut seen: HashSet<PathBuf> = filepaths
+        .iter()
+        .flat_map(|filepath| resolve_matches_or_path(filepath, opts))
+        .filter(|path| path.exists())
+        .collect();
+    let mut checks = 0;
+    loop {
+        ticker.count_check();
+        checks += 1;
+        debug!("Check {}", checks);
+        for filepath in filepaths {
+            for path in resolve_matches_or_path(filepath, opts) {
+                if seen.contains(&path) || !path.is_file() || !file_ready(&path.to_string_lossy(), opts) {
+                    continue;
+                }
+                info!("File '{}' appeared", path.display());
+                on_file(&path);
+                seen.insert(path);
+            }
+        }
+
+        if timed_out(start, opts.timeout) || out_of_checks(checks, opts.max_checks) {
+            info!("Stopped streaming after {:?}", start.elapsed());
+            return Ok(());
+        }
+        ticker.wait_at_most(ticker.interval, left(start, opts.timeout));
+    }
+}
+
+fn resolve_matches_or_path(filepath: &str, opts: &WatchOptions) -> Vec<PathBuf> {
+    match matches_any(filepath, opts) {
+        true => resolve_matches(filepath, opts),
+        false => vec![PathBuf::from(filepath)],
+    }
+}
+
+// --tail-match: read what is appended to the files, like tail -f, until a
+// line matches; a file is followed from its end at startup, one appearing
+// later (or replaced by rotation, or truncated) is read from the start
+
+struct Tail {
+    path: Option<PathBuf>,
+    id: Option<(u64, u64)>,
+    offset: u64,
+    // the unfinished last line, only whole lines are matched
+    partial: Vec<u8>,
+}
+
+pub(crate) fn wait_for_tail_match(filepaths: &[String], regex: &Regex, ticker: &Ticker, opts: &WatchOptions) -> Result<Vec<String>, WatchError> {
+    let mut tails = Vec::new();
+    for filepath in filepaths {
+        let mut tail = Tail { path: None, id: None, offset: 0, partial: Vec::new() };
+        if let Some(path) = tail_path(filepath, opts) {
+            let metadata = get_metadata(&path.to_string_lossy(), opts)?;
+            debug!("Following '{}' from byte {}", path.display(), metadata.len());
+            tail = Tail { path: Some(path), id: Some(file_id(&metadata)), offset: metadata.len(), partial: Vec::new() };
+        }
+        tails.push(tail);
+    }
+    let start = Instant::now();
+    let mut matched = Vec::new();
+    let mut checks = 0;
+    loop {
+        ticker.count_check();
+        checks += 1;
+        debug!("Check {}", checks);
+        let mut i = 0;
+        while i < tails.len() {
+            match read_tail(&filepaths[i], &mut tails[i], regex, opts)? {
+                Some(path) => {
+                    emit_event(opts.format, "line_matched", &path);
+                    matched.push(path);
+                    tails.remove(i);
+                    if opts.mode == Mode::Any || tails.is_empty() {
+                        return Ok(matched);
+                    }
+                }
+                None => i += 1,
+            }
+        }
+
+        if timed_out(start, opts.timeout) || out_of_checks(checks, opts.max_checks) {
+            warn!("No line of the files matched '{}' after waiting {:?}, giving up", regex, start.elapsed());
+            for filepath in filepaths {
+                emit_event(opts.format, "timed_out", filepath);
+            }
+            return Err(WatchError::Timeout);
+        }
+        if heartbeat_due(checks, opts.heartbeat) {
+            info!("Still waiting for a line matching '{}', {:?} elapsed", regex, start.elapsed());
+        }
+        ticker.wait_at_most(ticker.interval, left(start, opts.timeout));
+        if ticker.cancelled() {
+            return Err(WatchError::Timeout);
+        }
+    }
+}
+
+// the file a tailed path is, a pattern follows the match --match picks
+fn tail_path(filepath: &str, opts: &WatchOptions) -> Option<PathBuf> {
+    match matches_any(filepath, opts) {
+        true => pick(matches(filepath, opts), opts.match_policy, |_, metadata| metadata.is_file()),
+        false => Path::new(filepath).is_file().then(|| PathBuf::from(filepath)),
+    }
+}
+
+// read the new bytes of a tailed file, returns its path once a line matched
+fn read_tail(filepath: &str, tail: &mut Tail, regex: &Regex, opts: &WatchOptions) -> Result<Option<String>, WatchError> {
+    let Some(path) = tail_path(filepath, opts) else {
+        return Ok(None);
+    };
+    let file = path.to_string_lossy().into_owned();
+    let metadata = get_metadata(&file, opts)?;
+    let id = file_id(&metadata);
+    if tail.path.as_ref() != Some(&path) || tail.id != Some(id) || metadata.len() < tail.offset {
+        if tail.id.is_some() {
+            info!("File '{}' was rotated or truncated, reading it from the start", file);
+        }
+        *tail = Tail { path: Some(path), id: Some(id), offset: 0, partial: Vec::new() };
+    }
+    if metadata.len() == tail.offset {
+        return Ok(None);
+    }
+
+    let mut appended = Vec::new();
+    let read = File::open(&file).and_then(|mut f| {
+        io::Seek::seek(&mut f, io::SeekFrom::Start(tail.offset))?;
+        f.take(metadata.len() - tail.offset).read_to_end(&mut appended)
+    });
+    if let Err(e) = read {
+        debug!("Cannot read '{}': {}", file, e);
+        return Ok(None);
+    }
+    tail.offset += appended.len() as u64;
+    tail.partial.extend_from_slice(&appended);
+    let lines = tail.partial.split(|b| *b == b'\n');
+    let mut consumed = 0;
+    for line in lines {
+        if consumed + line.len() == tail.partial.len() {
+            break; // not finished yet
+        }
+        consumed += line.len() + 1;
+        let line = String::from_utf8_lossy(line);
+        if regex.is_match(line.trim_end_matches('\r')) {
+            info!("File '{}' has a line matching '{}': {}", file, regex, line);
+            return Ok(Some(file));
+        }
+    }
+    tail.partial.drain(..consumed);
+    // a runaway line without newlines only keeps its end
+    let max = opts.content_max_bytes as usize;
+    if tail.partial.len() > max {
+        tail.partial.drain(..tail.partial.len() - max);
+    }
+    Ok(None)
+}
+
+// second phase of --wait-complete, the files are there but may still be
+// written to, done once none of them grew for the window
+
+pub(crate) fn wait_until_complete(paths: &[String], window: Duration, ticker: &Ticker, opts: &WatchOptions, start: Instant) -> Result<(), WatchError> {
+    let mut sizes = Vec::new();
+    for path in paths {
+        debug!("Waiting for '{}' to stop growing for {:?}", path, window);
+        sizes.push((get_mod_and_len(path, opts)?.1, Instant::now()));
+    }
+    let mut delay = ticker.interval;
+    loop {
+        ticker.count_check();
+        let mut complete = true;
+        for (path, (len, since)) in paths.iter().zip(sizes.iter_mut()) {
+            let current = get_mod_and_len(path, opts)?.1;
+            // a shrinking file is being rewritten, that resets the window too
+            if current != *len {
+                debug!("File '{}' now has {} bytes, resetting quiet timer", path, current);
+                *len = current;
+                *since = Instant::now();
+            }
+            if since.elapsed() < window {
+                complete = false;
+            }
+        }
+        if complete {
+            for (path, (len, _)) in paths.iter().zip(&sizes) {
+                info!("File '{}' is complete with {} bytes", path, len);
+            }
+            return Ok(());
+        }
+
+        if timed_out(start, opts.timeout) {
+            warn!(
+                "File '{}' was still growing after waiting {:?}, giving up",
+                paths[0],
+                start.elapsed()
+            );
+            for path in paths {
+                emit_event(opts.format, "timed_out", path);
+            }
+            return Err(WatchError::Timeout);
+        }
+
+        // the window may end before the next event or interval
+        if ticker.wait_at_most(delay.min(window), left(start, opts.timeout)) {
+            delay = ticker.next_delay(delay);
+        }
+    }
+}
+
+// second phase of --dir-count and --dir-empty, the directories are there,
+// done once each has enough entries or none left
+
+pub(crate) fn wait_for_dir_entries(dirs: &[String], ticker: &Ticker, opts: &WatchOptions, start: Instant) -> Result<(), WatchError> {
+    let done = |found: u64| match opts.dir_count {
+        Some(count) => found >= count,
+        None => found == 0,
+    };
+    let wanted = match opts.dir_count {
+        Some(count) => format!("at least {} entries", count),
+        None => "no entries".to_string(),
+    };
+    let mut delay = ticker.interval;
+    let mut checks = 0;
+    loop {
+        ticker.count_check();
+        checks += 1;
+        // an unreadable directory is neither full nor empty yet
+        let counts: Vec<Option<u64>> = dirs.iter().map(|dir| count_entries(dir, opts)).collect();
+        if counts.iter().all(|found| found.is_some_and(done)) {
+            for (dir, found) in dirs.iter().zip(&counts) {
+                info!("Directory '{}' has {} entries", dir, found.unwrap_or(0));
+            }
+            return Ok(());
+        }
+
+        if timed_out(start, opts.timeout) || out_of_checks(checks, opts.max_checks) {
+            warn!("Directory '{}' did not have {} after waiting {:?}, giving up", dirs[0], wanted, start.elapsed());
+            for dir in dirs {
+                emit_event(opts.format, "timed_out", dir);
+            }
+            return Err(WatchError::Timeout);
+        }
+        if heartbeat_due(checks, opts.heartbeat) {
+            for (dir, found) in dirs.iter().zip(&counts) {
+                info!("Directory '{}' has {} entries, waiting for {}", dir, found.unwrap_or(0), wanted);
+            }
+        }
+        if ticker.wait_at_most(delay, left(start, opts.timeout)) {
+            delay = ticker.next_delay(delay);
+        }
+    }
+}
+
+// entries of a directory matching --dir-glob (hidden ones too unless
+// --ignore-hidden), a directory that cannot be read right now (e.g. being
+// replaced) is simply tried again at the next check
+
+fn count_entries(dir: &str, opts: &WatchOptions) -> Option<u64> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Cannot read '{}': {}", dir, e);
+            return None;
+        }
+    };
+    let count = entries
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            !(opts.ignore_hidden && name.starts_with('.'))
+                && opts.dir_glob.as_ref().is_none_or(|glob| glob.matches(&name))
+        })
+        .count();
+    Some(count as u64)
+}
+
+// last phase of --expect-sha256 (any --checksum), a mismatching file may still be written to
+// so it is hashed again whenever its mod time or size moved
+
+pub(crate) fn wait_for_checksum(paths: &[String], expected: &str, ticker: &Ticker, opts: &WatchOptions, start: Instant) -> Result<(), WatchError> {
+    wait_for_digest(paths, &mut || Ok(expected.to_string()), ticker, opts, start)
+}
+
+// --equals, like --expect-sha256 with the digest of the reference file, which
+// is hashed again whenever it changes
+
+pub(crate) fn wait_for_equal(paths: &[String], reference: &str, ticker: &Ticker, opts: &WatchOptions, start: Instant) -> Result<(), WatchError> {
+    let mut seen: Option<((Duration, u64), String)> = None;
+    let mut expected = || {
+        let current = get_mod_and_len(reference, opts)?;
+        if seen.as_ref().is_none_or(|(last, _)| *last != current) {
+            let digest = to_hex(&get_digest(reference, opts)?);
+            debug!("Reference '{}' has {} {}", reference, opts.checksum, digest);
+            seen = Some((current, digest));
+        }
+        Ok(seen.as_ref().map(|(_, digest)| digest.clone()).unwrap_or_default())
+    };
+    wait_for_digest(paths, &mut expected, ticker, opts, start)
+}
+
+// hash the files whenever their mod time or size changed until every digest
+// is the expected one, a file is only hashed once per version
+
+fn wait_for_digest(
+    paths: &[String],
+    expected: &mut dyn FnMut() -> Result<String, WatchError>,
+    ticker: &Ticker,
+    opts: &WatchOptions,
+    start: Instant,
+) -> Result<(), WatchError> {
+    let mut seen: Vec<Option<((Duration, u64), String)>> = vec![None; paths.len()];
+    let mut delay = ticker.interval;
+    loop {
+        ticker.count_check();
+        let expected = expected()?;
+        let mut matching = true;
+        for (path, seen) in paths.iter().zip(seen.iter_mut()) {
+            let current = get_mod_and_len(path, opts)?;
+            if seen.as_ref().is_none_or(|(last, _)| *last != current) {
+                let actual = to_hex(&get_digest(path, opts)?);
+                debug!("File '{}' has {} {}", path, opts.checksum, actual);
+                *seen = Some((current, actual));
+            }
+            if seen.as_ref().is_some_and(|(_, actual)| *actual != expected) {
+                matching = false;
+            }
+        }
+        if matching {
+            info!("Checksum of '{}' matches", paths.join("', '"));
+            return Ok(());
+        }
+
+        if timed_out(start, opts.timeout) {
+            for (path, seen) in paths.iter().zip(&seen) {
+                if let Some((_, actual)) = seen
+                    && *actual != expected
+                {
+                    error!("File '{}' has {} {}, expected {}", path, opts.checksum, actual, expected);
+                    emit_event(opts.format, "checksum_mismatch", path);
+                }
+            }
+            return Err(WatchError::ChecksumMismatch);
+        }
+
+        if ticker.wait_at_most(delay, left(start, opts.timeout)) {
+            delay = ticker.next_delay(delay);
+        }
+    }
+}
+
+// inverse of wait_for_file, a pattern is gone once nothing matches it, a file
+// that never existed counts as gone right away
+
+pub(crate) fn wait_for_file_gone(filepaths: &[String], ticker: &Ticker, opts: &WatchOptions) -> Result<Vec<String>, WatchError> {
+    let start = Instant::now();
+    let mut delay = ticker.interval;
+    let mut checks = 0;
+    loop {
+        ticker.count_check();
+        checks += 1;
+        debug!("Check {}", checks);
+        let (gone, pending): (Vec<&String>, Vec<&String>) =
+            filepaths.iter().partition(|filepath| file_gone(filepath, opts));
+        if pending.is_empty() || (opts.mode == Mode::Any && !gone.is_empty()) {
+            for filepath in &gone {
+                info!("File '{}' is gone, bye...", filepath);
+                emit_event(opts.format, "file_removed", filepath);
+            }
+            return Ok(gone.into_iter().cloned().collect());
+        }
+
+        if timed_out(start, opts.timeout) || out_of_checks(checks, opts.max_checks) {
+            warn!(
+                "File '{}' was not removed after waiting {:?}, giving up",
+                pending[0],
+                start.elapsed()
+            );
+            for filepath in &pending {
+                emit_event(opts.format, "timed_out", filepath);
+            }
+            return Err(WatchError::Timeout);
+        }
+        if heartbeat_due(checks, opts.heartbeat) {
+            info!("Still waiting for '{}' to be removed, {:?} elapsed", pending[0], start.elapsed());
+        }
+
+        let slept = ticker.wait_at_most(delay, left(start, opts.timeout));
+        if ticker.cancelled() {
+            return Err(WatchError::Timeout);
+        }
+        if slept {
+            delay = ticker.next_delay(delay);
+        }
+    }
+}
+
+pub(crate) fn file_gone(filepath: &str, opts: &WatchOptions) -> bool {
+    if matches_any(filepath, opts) {
+        return resolve_matches(filepath, opts).is_empty();
+    }
+    // unlike file_exists an error means we cannot tell, so keep waiting
+    match fs::exists(filepath) {
+        Ok(exists) => !exists,
+        Err(e) => {
+            debug!("Cannot check '{}': {}", filepath, e);
+            false
+        }
+    }
+}
+
+// check if file is present (and large enough), resolving wildcards, returns
+// the matched path, paths in skip do not count; directories matching a
+// pattern are passed over, a literal path turning up as one is an error
+
+pub(crate) fn file_available(filepath: &str, opts: &WatchOptions, skip: &HashSet<PathBuf>) -> Result<Option<String>, WatchError> {
+    if matches_any(filepath, opts) {
+        let picked = pick(matches(filepath, opts), opts.match_policy, |path, metadata| ready_match(path, metadata, opts, skip));
+        return Ok(picked.map(|path| path.to_string_lossy().into_owned()));
+    }
+    #[cfg(feature = "http")]
+    if filepath.starts_with("http://") || filepath.starts_with("https://") {
+        let available = crate::http::url_available(filepath, opts);
+        return Ok(available.then(|| filepath.to_string()));
+    }
+    #[cfg(feature = "s3")]
+    if filepath.starts_with("s3://") {
+        let available = crate::s3::object_available(filepath, opts);
+        return Ok(available.then(|| filepath.to_string()));
+    }
+    if opts.dir {
+        reject_file(filepath)?;
+        let ready = !skip.contains(Path::new(filepath)) && Path::new(filepath).is_dir();
+        return Ok(ready.then(|| filepath.to_string()));
+    }
+    reject_dir(filepath)?;
+    if skip.contains(Path::new(filepath)) || !file_exists(filepath) || !file_ready(filepath, opts) {
+        return Ok(None);
+    }
+    Ok(Some(filepath.to_string()))
+}
+
+// the files (directories with --dir) matching a pattern that count as ready
+
+fn ready_matches(filepath: &str, opts: &WatchOptions, skip: &HashSet<PathBuf>) -> Vec<PathBuf> {
+    resolve_matches(filepath, opts)
+        .into_iter()
+        .filter(|path| fs::metadata(path).is_ok_and(|metadata| ready_match(path, &metadata, opts, skip)))
+        .collect()
+}
+
+fn ready_match(path: &Path, metadata: &fs::Metadata, opts: &WatchOptions, skip: &HashSet<PathBuf>) -> bool {
+    !skip.contains(path)
+        && match opts.dir {
+            true => metadata.is_dir(),
+            false => !metadata.is_dir() && file_ready(&path.to_string_lossy(), opts),
+        }
+}
+
+// --min-size, --exact-size and --content-match on a file that exists
+
+fn file_ready(filepath: &str, opts: &WatchOptions) -> bool {
+    let len = || fs::metadata(filepath).map(|m| m.len()).unwrap_or(0);
+    if let Some(min_size) = opts.min_size {
+        let len = len();
+        if len < min_size {
+            debug!("File '{}' has {} of {} bytes", filepath, len, min_size);
+            return false;
+        }
+    }
+    if let Some(exact_size) = opts.exact_size
+        && !size_ready(filepath, len(), exact_size, opts.oversize_ready)
+    {
+        return false;
+    }
+    if let Some(regex) = &opts.content_match
+        && !content_matches(filepath, regex, opts.content_max_bytes)
+    {
+        return false;
+    }
+    true
+}
+
+// a file that grew past --exact-size is more likely broken than still being
+// written, so it is only ready with --oversize-ready
+
+pub(crate) fn size_ready(target: &str, len: u64, exact_size: u64, oversize_ready: bool) -> bool {
+    if len < exact_size {
+        debug!("'{}' has {} of {} bytes", target, len, exact_size);
+        return false;
+    }
+    if len > exact_size && !oversize_ready {
+        debug!("'{}' has {} bytes, more than the expected {}", target, len, exact_size);
+        return false;
+    }
+    true
+}
+
+// only files can be watched, shared by the up front check and the wait loops
+
+pub(crate) fn reject_dir(filepath: &str) -> Result<(), WatchError> {
+    if fs::metadata(filepath).is_ok_and(|metadata| metadata.is_dir()) {
+        error!("'{}' is a directory, only files can be watched", filepath);
+        return Err(WatchError::IsDir);
+    }
+    Ok(())
+}
+
+// the other way round for --dir
+
+fn reject_file(filepath: &str) -> Result<(), WatchError> {
+    if fs::metadata(filepath).is_ok_and(|metadata| !metadata.is_dir()) {
+        error!("'{}' is not a directory, only directories can be waited for with --dir", filepath);
+        return Err(WatchError::NotDir);
+    }
+    Ok(())
+}
+
+// --dir-missing error, the directory has to be there from the start
+
+pub(crate) fn require_dir(filepath: &str) -> Result<(), WatchError> {
+    reject_file(filepath)?;
+    if !Path::new(filepath).is_dir() {
+        error!("Directory '{}' does not exist", filepath);
+        return Err(WatchError::FileMissing);
+    }
+    Ok(())
+}
+
+// a reference file has to be there from the start
+
+pub(crate) fn require_file(filepath: &str) -> Result<(), WatchError> {
+    reject_dir(filepath)?;
+    if !file_exists(filepath) {
+        error!("Reference file '{}' does not exist", filepath);
+        return Err(WatchError::FileMissing);
+    }
+    Ok(())
+}
+
+// match the first max_bytes of the file, a file still being written simply
+// does not match yet
+
+fn content_matches(filepath: &str, regex: &Regex, max_bytes: u64) -> bool {
+    let mut content = Vec::new();
+    let read = File::open(filepath).and_then(|f| f.take(max_bytes).read_to_end(&mut content));
+    if let Err(e) = read {
+        debug!("Cannot read '{}': {}", filepath, e);
+        return false;
+    }
+    let matched = regex.is_match(&String::from_utf8_lossy(&content));
+    if !matched {
+        debug!("File '{}' does not match '{}' yet", filepath, regex);
+    }
+    matched
+}
+
+// an error checking for the file (e.g. EACCES on the parent) counts as not
+// available yet, a persistent one is left to the timeout
+
+fn file_exists(filepath: &str) -> bool {
+    match fs::exists(filepath) {
+        Ok(exists) => exists,
+        Err(e) => {
+            debug!("Cannot check '{}': {}", filepath, e);
+            false
+        }
+    }
+}
+
+fn timed_out(start: Instant, timeout: Option<Duration>) -> bool {
+    timeout.is_some_and(|limit| start.elapsed() >= limit)
+}
+
+// how long the timeout still leaves, no sleep may go past it
+
+fn left(start: Instant, timeout: Option<Duration>) -> Option<Duration> {
+    timeout.map(|limit| limit.saturating_sub(start.elapsed()))
+}
+
+// a sign of life every heartbeat checks, so a long wait does not look hung
+
+fn heartbeat_due(checks: u64, heartbeat: u64) -> bool {
+    heartbeat > 0 && checks.is_multiple_of(heartbeat)
+}
+
+fn out_of_checks(checks: u64, max_checks: Option<u64>) -> bool {
+    max_checks.is_some_and(|max| checks >= max)
+}
+
+fn get_last_mod(file: &str, opts: &WatchOptions) -> Result<Duration, WatchError> {
+    let metadata = get_metadata(file, opts)?;
+    if !metadata.is_dir() {
+        let last_mod = file_time(file, &metadata, opts.time_field)?;
+        debug!("Duration till last mod: {:?}", last_mod);
+        Ok(last_mod)
+    } else {
+        warn!("Cannot check file presence, '{}' is a directory. Exiting", file);
+        Err(WatchError::IsDir)
+    }
+}
+
+fn get_mod_and_len(file: &str, opts: &WatchOptions) -> Result<(Duration, u64), WatchError> {
+    let last_mod = get_last_mod(file, opts)?;
+    Ok((last_mod, get_metadata(file, opts)?.len()))
+}
+
+// metadata of a file that should be there, a file we may not look at is not
+// a missing one; with --no-follow a symlink is looked at itself, so
+// repointing it is an update
+
+fn get_metadata(file: &str, opts: &WatchOptions) -> Result<fs::Metadata, WatchError> {
+    let metadata = match opts.no_follow {
+        true => fs::symlink_metadata(file),
+        false => fs::metadata(file),
+    };
+    metadata.map_err(|e| metadata_error(file, &e))
+}
+
+fn metadata_error(file: &str, e: &io::Error) -> WatchError {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => {
+            error!("Permission denied on '{}': {}", file, e);
+            WatchError::PermissionDenied
+        }
+        _ => {
+            error!("File '{}' went missing :(, restart again if you want to wait for it's arrival ({})", file, e);
+            WatchError::FileMissing
+        }
+    }
+}
+
+// where a followed symlink ends up, a repointed link counts as an update
+// even when the new target looks like the old one
+
+fn link_target(file: &str, opts: &WatchOptions) -> Option<PathBuf> {
+    match opts.no_follow {
+        true => None,
+        false => fs::canonicalize(file).ok(),
+    }
+}
+
+// identity of the file behind the path, a file renamed over the path gets a
+// new one even if its mod time is not newer
+
+fn get_file_id(file: &str, opts: &WatchOptions) -> Result<(u64, u64), WatchError> {
+    Ok(file_id(&get_metadata(file, opts)?))
+}
+
+#[cfg(unix)]
+pub(crate) fn file_id(metadata: &fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+// no inode to go by, fall back to the size (the mod time is compared anyway)
+#[cfg(not(unix))]
+pub(crate) fn file_id(metadata: &fs::Metadata) -> (u64, u64) {
+    (metadata.len(), 0)
+}
+
+// --checksum digest of the file
+
+fn get_digest(file: &str, opts: &WatchOptions) -> Result<Vec<u8>, WatchError> {
+    let digest = File::open(file).and_then(|f| digest_file(&f, opts.checksum));
+    digest.map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => metadata_error(file, &e),
+        _ => {
+            error!("Cannot read '{}': {}", file, e);
+            WatchError::FileMissing
+        }
+    })
+}
+
+// the timestamp --time-field selects, as time since the epoch
+
+fn file_time(file: &str, metadata: &fs::Metadata, field: TimeField) -> Result<Duration, WatchError> {
+    let time = match field {
+        TimeField::Mtime => metadata.modified(),
+        TimeField::Atime => metadata.accessed(),
+        TimeField::Ctime => return change_time(file, metadata),
+    };
+    let time = time.map_err(|e| {
+        error!("Cannot get the {:?} of '{}': {}", field, file, e);
+        WatchError::InvalidTime
+    })?;
+    since_epoch(file, time)
+}
+
+#[cfg(unix)]
+fn change_time(file: &str, metadata: &fs::Metadata) -> Result<Duration, WatchError> {
+    use std::os::unix::fs::MetadataExt;
+    match u64::try_from(metadata.ctime()) {
+        Ok(secs) => Ok(Duration::new(secs, metadata.ctime_nsec() as u32)),
+        Err(_) => {
+            error!("Change time of '{}' is before the epoch, check the clock", file);
+            Err(WatchError::InvalidTime)
+        }
+    }
+}
+
+// no inode change time here, the mod time is the closest there is
+#[cfg(not(unix))]
+fn change_time(file: &str, metadata: &fs::Metadata) -> Result<Duration, WatchError> {
+    file_time(file, metadata, TimeField::Mtime)
+}
+
+// mod time since the epoch, with whatever sub-second precision the
+// filesystem keeps, so two writes within a second still differ; a time
+// before the epoch means a broken clock, no use comparing against it
+
+fn since_epoch(file: &str, modified: SystemTime) -> Result<Duration, WatchError> {
+    modified.duration_since(UNIX_EPOCH).map_err(|e| {
+        error!("Mod time of '{}' is {:?} before the epoch, check the clock", file, e.duration());
+        WatchError::InvalidTime
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fixed mod time in whole seconds, so restoring it restores it exactly
+    fn past() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+    }
+
+    fn write(path: &Path, content: &str) {
+        fs::write(path, content).unwrap();
+        File::options().write(true).open(path).unwrap().set_modified(past()).unwrap();
+    }
+
+    // whether the change counts as an update with only these signals detected
+    fn updated(detect: &[ChangeSignal], change: impl Fn(&Path)) -> bool {
+        updated_with(WatchOptions { detect: detect.to_vec(), ..WatchOptions::default() }, change)
+    }
+
+    fn updated_with(opts: WatchOptions, change: impl Fn(&Path)) -> bool {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("in.csv");
+        write(&path, "a,b\n");
+        let filename = path.to_string_lossy();
+        let mut state = baseline(&filename, &opts).unwrap();
+        change(&path);
+        check_update(&filename, &mut state, &opts).unwrap().is_some()
+    }
+
+    fn newer_mod_time(path: &Path) {
+        File::options().write(true).open(path).unwrap().set_modified(SystemTime::now()).unwrap();
+    }
+
+    // rewritten in place, same file and mod time
+    fn truncated(path: &Path) {
+        write(path, "a");
+    }
+
+    // same size and mod time, another file
+    #[cfg(unix)]
+    fn replaced(path: &Path) {
+        let copy = path.with_extension("tmp");
+        write(&copy, "a,b\n");
+        fs::rename(copy, path).unwrap();
+    }
+
+    #[test]
+    fn mtime_alone_detects_a_newer_mod_time() {
+        assert!(updated(&[ChangeSignal::Mtime], newer_mod_time));
+        assert!(!updated(&[ChangeSignal::Size, ChangeSignal::Inode], newer_mod_time));
+    }
+
+    #[test]
+    fn size_alone_detects_a_truncation() {
+        assert!(updated(&[ChangeSignal::Size], truncated));
+        assert!(!updated(&[ChangeSignal::Mtime, ChangeSignal::Inode], truncated));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn inode_alone_detects_a_replaced_file() {
+        assert!(updated(&[ChangeSignal::Inode], replaced));
+        assert!(!updated(&[ChangeSignal::Mtime, ChangeSignal::Size], replaced));
+    }
+
+    #[test]
+    fn ignore_touch_keeps_waiting_through_a_touch() {
+        let opts = || WatchOptions { ignore_touch: true, ..WatchOptions::default() };
+        assert!(!updated_with(opts(), newer_mod_time));
+        assert!(updated_with(opts(), |path| {
+            fs::write(path, "a,c\n").unwrap();
+            newer_mod_time(path);
+        }));
+    }
+
+    // wait_for_file on one path, with what happens to it meanwhile done
+    // from another thread after a short delay; also the size of the path the
+    // moment the wait returned
+    fn wait_while(
+        path: &Path,
+        opts: WatchOptions,
+        meanwhile: impl FnOnce() + Send + 'static,
+    ) -> (Result<Vec<String>, WatchError>, Option<u64>) {
+        let filepaths = [path.to_string_lossy().into_owned()];
+        let opts = WatchOptions { interval: Duration::from_millis(50), timeout: Some(Duration::from_secs(10)), ..opts };
+        let ticker = Ticker::new(&filepaths, &opts);
+        let meanwhile = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            meanwhile();
+        });
+        let res = wait_for_file(&filepaths, &ticker, &opts);
+        let len = fs::metadata(path).ok().map(|metadata| metadata.len());
+        meanwhile.join().unwrap();
+        (res, len)
+    }
+
+    #[test]
+    fn non_empty_waits_for_the_first_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("in.csv");
+        fs::write(&path, "").unwrap();
+        let written = path.clone();
+        let (res, len) = wait_while(&path, WatchOptions { min_size: Some(1), ..WatchOptions::default() }, move || {
+            fs::write(written, "a,b\n").unwrap();
+        });
+        assert_eq!(res, Ok(vec![path.to_string_lossy().into_owned()]));
+        assert_eq!(len, Some(4));
+    }
+
+    #[test]
+    fn a_mod_time_before_the_epoch_is_an_error() {
+        assert_eq!(since_epoch("in.csv", UNIX_EPOCH - Duration::from_secs(1)), Err(WatchError::InvalidTime));
+        assert_eq!(since_epoch("in.csv", past()), Ok(Duration::from_secs(1_600_000_000)));
+    }
+
+    #[test]
+    fn a_missing_parent_directory_is_waited_for() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a/b/in.csv");
+        let created = path.clone();
+        let (res, len) = wait_while(&path, WatchOptions::default(), move || {
+            fs::create_dir_all(created.parent().unwrap()).unwrap();
+            fs::write(created, "a,b\n").unwrap();
+        });
+        assert_eq!(res, Ok(vec![path.to_string_lossy().into_owned()]));
+        assert!(len.is_some());
+        let opts = WatchOptions { timeout: Some(Duration::from_millis(200)), ..WatchOptions::default() };
+        let missing = [dir.path().join("c/in.csv").to_string_lossy().into_owned()];
+        assert_eq!(wait_for_file(&missing, &Ticker::new(&missing, &opts), &opts), Err(WatchError::Timeout));
+    }
+
+    // whether the change to dir counts as an update of dir/current, a
+    // symlink to dir/a; dir/b is alike in size and mod time
+    #[cfg(unix)]
+    fn link_updated(no_follow: bool, change: impl Fn(&Path)) -> bool {
+        let dir = tempfile::tempdir().unwrap();
+        write(&dir.path().join("a"), "a,b\n");
+        write(&dir.path().join("b"), "a,b\n");
+        let link = dir.path().join("current");
+        std::os::unix::fs::symlink(dir.path().join("a"), &link).unwrap();
+        let filename = link.to_string_lossy();
+        let opts = WatchOptions { no_follow, ..WatchOptions::default() };
+        let mut state = baseline(&filename, &opts).unwrap();
+        change(dir.path());
+        check_update(&filename, &mut state, &opts).unwrap().is_some()
+    }
+
+    // swapped for one to b the way deploys do it, by renaming a new link over it
+    #[cfg(unix)]
+    fn repointed(dir: &Path) {
+        std::os::unix::fs::symlink(dir.join("b"), dir.join("next")).unwrap();
+        fs::rename(dir.join("next"), dir.join("current")).unwrap();
+    }
+
+    #[cfg(unix)]
+    fn target_written(dir: &Path) {
+        fs::write(dir.join("a"), "a,b,c\n").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_followed_symlink_updates_with_its_target_and_when_repointed() {
+        assert!(link_updated(false, repointed));
+        assert!(link_updated(false, target_written));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn no_follow_only_updates_when_the_symlink_is_repointed() {
+        assert!(link_updated(true, repointed));
+        assert!(!link_updated(true, target_written));
+    }
+}
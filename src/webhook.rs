@@ -0,0 +1,29 @@
+use log::{info, warn};
+use serde_json::json;
+use std::path::Path;
+use std::time::Duration;
+use ureq::Agent;
+
+// POST {filename, event, waited_secs} to the url, a failed delivery is only
+// logged so it never changes the outcome of the wait
+
+pub fn send_webhook(url: &str, filename: &Path, event: &str, waited: Duration, timeout: Duration) {
+    let agent: Agent = Agent::config_builder()
+        .timeout_global(Some(timeout))
+        .build()
+        .into();
+    let body = json!({
+        "filename": filename.to_string_lossy(),
+        "event": event,
+        "waited_secs": waited.as_secs_f64(),
+    });
+
+    match agent
+        .post(url)
+        .header("Content-Type", "application/json")
+        .send(body.to_string())
+    {
+        Ok(response) => info!("Webhook '{}' answered {}", url, response.status()),
+        Err(e) => warn!("Failed to deliver webhook to '{}': {}", url, e),
+    }
+}
@@ -0,0 +1,194 @@
+// the binary as a script sees it: arguments in, exit code and stdout out
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn file_watcher(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_file_watcher"))
+        .args(args)
+        .output()
+        .expect("cannot run file_watcher")
+}
+
+fn arg(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[cfg(unix)]
+#[test]
+fn exec_gets_the_resolved_path_as_one_argument() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("a b'c.tar.gz");
+    std::fs::write(&file, "").unwrap();
+    let output = file_watcher(&["-f", arg(&dir.path().join("a*")), "--no-lock", "-e", "printf '%s\\n' {} {base} {ext} x{dir}x"]);
+    assert_eq!(output.status.code(), Some(0));
+    let expected = format!("{}\na b'c.tar.gz\ngz\nx{}x\n", file.display(), dir.path().display());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+}
+
+#[test]
+fn until_gone_exits_0_once_the_file_is_removed() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("in.csv");
+    std::fs::write(&file, "").unwrap();
+    let remove = {
+        let file = file.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            std::fs::remove_file(file).unwrap();
+        })
+    };
+    let output = file_watcher(&["-f", arg(&file), "--until-gone", "--no-lock", "-i", "100ms", "-t", "10s"]);
+    remove.join().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn until_gone_of_a_file_that_never_existed_exits_0_right_away() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = file_watcher(&["-f", arg(&dir.path().join("in-*.csv")), "--until-gone", "--no-lock", "-t", "1s"]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+// the code as the shell sees it in $?, not just what the process reports
+#[cfg(unix)]
+fn shell_exit_code(args: &[&str]) -> String {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg("\"$0\" \"$@\" >/dev/null 2>&1; echo $?")
+        .arg(env!("CARGO_BIN_EXE_file_watcher"))
+        .args(args)
+        .output()
+        .expect("cannot run sh");
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+#[cfg(unix)]
+#[test]
+fn exit_codes_reach_the_shell() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing.csv");
+    assert_eq!(shell_exit_code(&["-f", arg(&missing), "--no-lock", "-t", "200ms"]), "4");
+    assert_eq!(shell_exit_code(&["-f", arg(&missing), "--no-lock", "-t", "200ms", "--exit-on-timeout", "42"]), "42");
+    assert_eq!(shell_exit_code(&["-f", arg(dir.path()), "--no-lock"]), "2");
+    assert_eq!(shell_exit_code(&["-f", arg(&missing), "--no-such-option"]), "64");
+    assert_eq!(shell_exit_code(&["--help"]), "0");
+}
+
+#[cfg(unix)]
+#[test]
+fn a_held_lock_exits_1() {
+    let dir = tempfile::tempdir().unwrap();
+    let locks = dir.path().join("locks");
+    let missing = dir.path().join("missing.csv");
+    let mut holder = Command::new(env!("CARGO_BIN_EXE_file_watcher"))
+        .args(["-f", arg(&missing), "--lock-dir", arg(&locks), "-t", "10s"])
+        .spawn()
+        .expect("cannot run file_watcher");
+    let start = std::time::Instant::now();
+    while std::fs::read_dir(&locks).map_or(true, |mut entries| entries.next().is_none()) {
+        assert!(start.elapsed() < std::time::Duration::from_secs(5), "no lock file appeared");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    let code = shell_exit_code(&["-f", arg(&missing), "--lock-dir", arg(&locks), "-t", "200ms"]);
+    holder.kill().unwrap();
+    holder.wait().unwrap();
+    assert_eq!(code, "1");
+}
+
+fn assert_cannot_lock(output: &Output) {
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--lock-dir"));
+}
+
+#[test]
+fn an_unusable_lock_dir_exits_1_and_suggests_lock_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing.csv");
+    let not_a_dir = dir.path().join("file");
+    std::fs::write(&not_a_dir, "").unwrap();
+    assert_cannot_lock(&file_watcher(&["-f", arg(&missing), "--lock-dir", arg(&not_a_dir.join("locks")), "-t", "1s"]));
+}
+
+#[cfg(unix)]
+#[test]
+fn an_unusable_home_exits_1_and_suggests_lock_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing.csv");
+    let run_with_home = |home: &Path| {
+        Command::new(env!("CARGO_BIN_EXE_file_watcher"))
+            .args(["-f", arg(&missing), "-t", "1s"])
+            .env("HOME", home)
+            .env_remove("XDG_RUNTIME_DIR")
+            .env_remove("XDG_STATE_HOME")
+            .output()
+            .expect("cannot run file_watcher")
+    };
+    assert_cannot_lock(&run_with_home(&dir.path().join("no-such-home")));
+    // exists, but nothing may be created in it
+    #[cfg(target_os = "linux")]
+    assert_cannot_lock(&run_with_home(Path::new("/proc")));
+}
+
+// the interval a -v run logged it polls at
+fn polling_every(output: &Output) -> Option<String> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stderr.lines().find_map(|line| line.split_once("Polling every ").map(|(_, every)| every.to_string()))
+}
+
+#[test]
+fn a_flag_beats_the_environment_which_beats_the_default() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing.csv");
+    let interval = |args: &[&str], env: Option<&str>| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_file_watcher"));
+        command.args(["-f", arg(&missing), "--no-lock", "--poll", "-t", "100ms", "-v"]).args(args);
+        command.env_remove("FILEWATCHER_INTERVAL");
+        if let Some(interval) = env {
+            command.env("FILEWATCHER_INTERVAL", interval);
+        }
+        polling_every(&command.output().expect("cannot run file_watcher"))
+    };
+    assert_eq!(interval(&["-i", "3s"], Some("7s")).as_deref(), Some("3s"));
+    assert_eq!(interval(&[], Some("7s")).as_deref(), Some("7s"));
+    assert_eq!(interval(&[], None).as_deref(), Some("10s"));
+}
+
+#[test]
+fn a_flag_overrides_one_option_of_the_config_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing.csv");
+    let config = dir.path().join("filewatcher.toml");
+    std::fs::write(&config, "interval = \"7s\"\ntimeout = \"100ms\"\npoll = true\nno_lock = true\n").unwrap();
+    let output = file_watcher(&["-f", arg(&missing), "--config", arg(&config), "-v"]);
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(polling_every(&output).as_deref(), Some("7s"));
+    let output = file_watcher(&["-f", arg(&missing), "--config", arg(&config), "-v", "-i", "3s"]);
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(polling_every(&output).as_deref(), Some("3s"));
+}
+
+#[cfg(unix)]
+#[test]
+fn writes_within_the_debounce_window_run_exec_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("in.csv");
+    let runs = dir.path().join("runs");
+    std::fs::write(&file, "").unwrap();
+    let writes = {
+        let file = file.clone();
+        std::thread::spawn(move || {
+            for i in 0..3 {
+                std::thread::sleep(std::time::Duration::from_millis(300));
+                std::fs::write(&file, format!("{}\n", i)).unwrap();
+            }
+        })
+    };
+    let exec = format!("echo run >> '{}'", runs.display());
+    let output = file_watcher(&[
+        "-f", arg(&file), "--update", "--watch", "--debounce", "1s", "--no-lock", "-i", "100ms", "-t", "2s", "--exec-shell", "-e", &exec,
+    ]);
+    writes.join().unwrap();
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(std::fs::read_to_string(runs).unwrap(), "run\n");
+}